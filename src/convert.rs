@@ -0,0 +1,123 @@
+use clap::ValueEnum;
+use std::collections::BTreeMap;
+
+use crate::tree::{Kind, Node, Scalar};
+use crate::{dotenv, json, toml, yaml, Error, Result};
+
+/// Config formats the output can be converted to
+#[derive(Debug, Clone, ValueEnum, Copy, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    Yaml,
+    Toml,
+    Dotenv,
+}
+
+impl Format {
+    /// Serialize the printed `(key, value)` pairs, nesting keys on `separator`
+    /// for tree shaped formats.
+    ///
+    /// # Errors
+    ///
+    /// Fails when keys conflict once nested or the format can't represent the tree.
+    pub fn serialize(self, pairs: Vec<(String, Scalar)>, separator: char) -> Result<String> {
+        match self {
+            Format::Json => json::to_string(&unflatten(pairs, separator)?),
+            Format::Yaml => yaml::to_string(&unflatten(pairs, separator)?),
+            Format::Toml => toml::to_string(&unflatten(pairs, separator)?),
+            Format::Dotenv => Ok(dotenv::to_string(&pairs)),
+        }
+    }
+
+    /// Whether keys are nested back into a tree instead of kept flat
+    #[must_use]
+    pub fn is_tree(self) -> bool {
+        self != Format::Dotenv
+    }
+}
+
+impl std::fmt::Display for Format {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Format::Json => write!(f, "json"),
+            Format::Yaml => write!(f, "yaml"),
+            Format::Toml => write!(f, "toml"),
+            Format::Dotenv => write!(f, "dotenv"),
+        }
+    }
+}
+
+/// A number as the narrowest native type it fits in.
+///
+/// Numbers need to go through their textual form since they don't serialize
+/// to other formats as is.
+pub(crate) enum Number {
+    Int(i64),
+    Uint(u64),
+    Float(f64),
+}
+
+impl Number {
+    pub(crate) fn parse(text: &str) -> Option<Self> {
+        text.parse()
+            .map(Number::Int)
+            .or_else(|_| text.parse().map(Number::Uint))
+            .or_else(|_| text.parse().map(Number::Float))
+            .ok()
+    }
+}
+
+/// Rebuild a tree out of flat keys, the inverse of [`Node::leaves`].
+///
+/// Maps whose keys are exactly `0..n` turn back into sequences.
+fn unflatten(pairs: Vec<(String, Scalar)>, separator: char) -> Result<Node> {
+    let mut root = Node::map(BTreeMap::new());
+    for (key, value) in pairs {
+        let conflict = || {
+            Error::KeyCollision(format!(
+                "Key `{key}` can't be nested, a parent of it is already a value"
+            ))
+        };
+        let mut segments = key.split(separator).collect::<Vec<_>>();
+        let last = segments.pop().unwrap_or_default();
+
+        let mut node = &mut root;
+        for segment in segments {
+            node = match &mut node.kind {
+                Kind::Map(map) => map
+                    .entry(segment.to_owned())
+                    .or_insert_with(|| Node::map(BTreeMap::new())),
+                _ => return Err(conflict()),
+            };
+        }
+        match &mut node.kind {
+            Kind::Map(map) if !map.contains_key(last) => {
+                map.insert(last.to_owned(), value.into());
+            }
+            _ => return Err(conflict()),
+        }
+    }
+    Ok(into_seqs(root))
+}
+
+fn into_seqs(node: Node) -> Node {
+    let Kind::Map(mut map) = node.kind else {
+        return node;
+    };
+    let is_seq =
+        !map.is_empty() && (0..map.len()).all(|idx| map.contains_key(idx.to_string().as_str()));
+    if is_seq {
+        Node::seq(
+            (0..map.len())
+                .filter_map(|idx| map.remove(idx.to_string().as_str()))
+                .map(into_seqs)
+                .collect(),
+        )
+    } else {
+        Node::map(
+            map.into_iter()
+                .map(|(key, node)| (key, into_seqs(node)))
+                .collect(),
+        )
+    }
+}
@@ -0,0 +1,120 @@
+use std::collections::BTreeMap;
+use std::io::Read;
+
+use crate::tree::{Kind, Node, Scalar, Span};
+use crate::{Error, Result};
+
+/// Delimiters we look for when sniffing the header row
+const CANDIDATE_DELIMITERS: [u8; 4] = [b',', b'\t', b';', b'|'];
+
+/// Parse csv/tsv data where the first row holds the field names.
+///
+/// Rows are returned as an array of records, or as a map keyed by the values
+/// of `key_column` (which is then left out of each record). The delimiter is
+/// guessed from the header row unless given.
+///
+/// # Errors
+///
+/// Fails when the input is not valid csv, `key_column` is not in the header
+/// or has duplicate values.
+pub fn from_str(input: &str, key_column: Option<&str>, delimiter: Option<u8>) -> Result<Node> {
+    let mut reader = ::csv::ReaderBuilder::new()
+        .delimiter(delimiter.unwrap_or_else(|| guess_delimiter(input)))
+        .from_reader(input.as_bytes());
+    let headers = reader.headers()?.clone();
+    let key_index = key_column
+        .map(|column| {
+            headers
+                .iter()
+                .position(|header| header == column)
+                .ok_or_else(|| {
+                    Error::InvalidInputFormat(format!("Column `{column}` not found in csv header"))
+                })
+        })
+        .transpose()?;
+
+    let mut rows = Vec::new();
+    let mut keyed_rows = BTreeMap::new();
+    for record in reader.records() {
+        let record = record?;
+        // Fields don't know their column, point them at the start of their row
+        let span = record.position().map(|position| Span {
+            line: usize::try_from(position.line()).unwrap_or(usize::MAX),
+            column: 1,
+        });
+        let node = |kind| Node {
+            span,
+            ..Node::new(kind)
+        };
+
+        let mut row = BTreeMap::new();
+        for (idx, (header, field)) in headers.iter().zip(record.iter()).enumerate() {
+            if Some(idx) != key_index {
+                row.insert(
+                    header.to_owned(),
+                    node(Kind::Scalar(Scalar::String(field.to_owned()))),
+                );
+            }
+        }
+        if let Some(idx) = key_index {
+            let key = record.get(idx).unwrap_or_default().to_owned();
+            if keyed_rows.contains_key(&key) {
+                return Err(Error::InvalidInputFormat(format!(
+                    "Duplicate value `{key}` in csv key column"
+                )));
+            }
+            keyed_rows.insert(key, node(Kind::Map(row)));
+        } else {
+            rows.push(node(Kind::Map(row)));
+        }
+    }
+
+    Ok(if key_index.is_some() {
+        Node::map(keyed_rows)
+    } else {
+        Node::seq(rows)
+    })
+}
+
+/// See [`from_str`]
+///
+/// # Errors
+///
+/// Same as [`from_str`], or when the input can't be read.
+pub fn from_reader(
+    mut read: impl Read,
+    key_column: Option<&str>,
+    delimiter: Option<u8>,
+) -> Result<Node> {
+    // Sniffing the delimiter needs the data upfront
+    let mut input = String::new();
+    read.read_to_string(&mut input)?;
+    from_str(&input, key_column, delimiter)
+}
+
+/// Pick the candidate delimiter that shows up the most in the header row,
+/// ignoring anything between quotes. Defaults to a comma.
+fn guess_delimiter(input: &str) -> u8 {
+    let mut counts = [0_usize; CANDIDATE_DELIMITERS.len()];
+    let mut quoted = false;
+    for byte in input.bytes() {
+        match byte {
+            b'"' => quoted = !quoted,
+            b'\n' if !quoted => break,
+            _ if !quoted => {
+                if let Some(idx) = CANDIDATE_DELIMITERS.iter().position(|&d| d == byte) {
+                    counts[idx] += 1;
+                }
+            }
+            _ => (),
+        }
+    }
+    counts
+        .iter()
+        .enumerate()
+        // `max_by_key` keeps the last max, reverse so ties go to the earliest candidate
+        .rev()
+        .max_by_key(|(_, &count)| count)
+        .filter(|(_, &count)| count > 0)
+        .map_or(b',', |(idx, _)| CANDIDATE_DELIMITERS[idx])
+}
@@ -0,0 +1,795 @@
+use std::collections::{btree_map, BTreeMap};
+use std::fmt::Write as _;
+use std::io::Read;
+use std::ops::Range;
+use std::sync::Arc;
+
+use clap::ValueEnum;
+use regex::Regex;
+use std::sync::LazyLock;
+
+use crate::error::{offset_at, span_at};
+use crate::input::{self, Format, Input};
+use crate::query::Query;
+use crate::template::{self, Missing, Pattern};
+use crate::tree::{Kind, Node, Scalar, Span};
+use crate::{csv, dotenv, json, toml, xml, yaml};
+use crate::{Error, Result};
+
+/// `$VAR` placeholders in match templates, `*` and `{}` included, e.g. `$HOST_*`,
+/// along with a `:-default` or a `!` required marker
+pub(crate) static PLACEHOLDER: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"\$\s*((?:\w|\*|\{\})+(?::-[^\s,;"'\\<>\])}]*|!)?)"#).unwrap());
+
+/// A match template whose placeholders were replaced so it parses in its format
+struct Fixed {
+    text: String,
+    /// Byte range of each placeholder in the template, and of its replacement
+    /// in `text`
+    replaced: Vec<(Range<usize>, Range<usize>)>,
+}
+
+impl Fixed {
+    /// Replace the `$VAR` placeholders of `template` with their names, quoted
+    /// along with bare `_` skip markers for formats where a bare name is not a string
+    fn new(template: &str, quote: bool) -> Self {
+        let mut names = PLACEHOLDER
+            .captures_iter(template)
+            .map(|caps| (caps.get(0).unwrap().range(), caps.get(1).unwrap().as_str()))
+            .collect::<Vec<_>>();
+        if quote {
+            names.extend(bare_skips(template).into_iter().map(|range| (range, "_")));
+            names.sort_by_key(|(range, _)| range.start);
+        }
+        let mut text = String::with_capacity(template.len());
+        let mut replaced = Vec::new();
+        let mut last = 0;
+        for (placeholder, name) in names {
+            text.push_str(&template[last..placeholder.start]);
+            let start = text.len();
+            if quote {
+                let _ = write!(text, r#""{name}""#);
+            } else {
+                text.push_str(name);
+            }
+            last = placeholder.end;
+            replaced.push((placeholder, start..text.len()));
+        }
+        text.push_str(&template[last..]);
+        Self { text, replaced }
+    }
+
+    /// Byte offset in the template of `offset` in the fixed text, placeholders
+    /// as a whole
+    fn original(&self, offset: usize) -> usize {
+        let (mut template, mut fixed) = (0, 0);
+        for (placeholder, replacement) in &self.replaced {
+            if offset < replacement.start {
+                break;
+            }
+            if offset < replacement.end {
+                return placeholder.start;
+            }
+            (template, fixed) = (placeholder.end, replacement.end);
+        }
+        template + (offset - fixed)
+    }
+}
+
+/// `_` skip markers standing alone as a sequence item or value, outside strings
+fn bare_skips(template: &str) -> Vec<Range<usize>> {
+    let mut skips = Vec::new();
+    let mut quote = None;
+    let mut escaped = false;
+    for (idx, byte) in template.bytes().enumerate() {
+        if let Some(open) = quote {
+            if escaped {
+                escaped = false;
+            } else if byte == b'\\' && open == b'"' {
+                escaped = true;
+            } else if byte == open {
+                quote = None;
+            }
+            continue;
+        }
+        match byte {
+            b'"' | b'\'' => quote = Some(byte),
+            b'_' => {
+                let before = template[..idx].trim_end().bytes().last();
+                let after = template[idx + 1..]
+                    .trim_start_matches([' ', '\t'])
+                    .bytes()
+                    .next();
+                if matches!(before, Some(b'[' | b',' | b':' | b'='))
+                    && matches!(after, None | Some(b',' | b']' | b'}' | b'\n' | b'\r'))
+                {
+                    skips.push(idx..idx + 1);
+                }
+            }
+            _ => {}
+        }
+    }
+    skips
+}
+
+/// Format specific parsing knobs
+#[derive(Debug, Clone)]
+pub struct ParseOptions {
+    /// Prefix for xml attribute keys
+    pub attribute_prefix: String,
+    /// Key csv rows by the values of this column instead of by their index
+    pub key_column: Option<String>,
+    /// Delimiter of csv data, guessed from the header row when `None`
+    pub delimiter: Option<u8>,
+    /// How .env values expand variables
+    pub expansion: dotenv::Expansion,
+}
+
+impl Default for ParseOptions {
+    fn default() -> Self {
+        Self {
+            attribute_prefix: "@".to_owned(),
+            key_column: None,
+            delimiter: None,
+            expansion: dotenv::Expansion::default(),
+        }
+    }
+}
+
+/// A parsed config file, whatever format it came from
+#[derive(Debug, Clone)]
+pub struct Document {
+    format: Format,
+    options: ParseOptions,
+    tree: Node,
+    warnings: Vec<String>,
+    // Separator a merged .env tree got nested on, its templates are nested the same way
+    nested_on: Option<char>,
+}
+
+impl Document {
+    /// Parse `read` as `format`.
+    ///
+    /// # Errors
+    ///
+    /// Fails when the data is not valid `format`.
+    pub fn from_reader(read: impl Read, format: Format, options: ParseOptions) -> Result<Self> {
+        let (tree, warnings) = match format {
+            Format::Json => (json::from_reader(read)?, Vec::new()),
+            Format::Yaml => (yaml::from_reader(read)?, Vec::new()),
+            Format::Toml => (toml::from_reader(read)?, Vec::new()),
+            Format::Xml => (
+                xml::from_reader(read, &options.attribute_prefix)?,
+                Vec::new(),
+            ),
+            Format::Csv => (
+                csv::from_reader(read, options.key_column.as_deref(), options.delimiter)?,
+                Vec::new(),
+            ),
+            Format::Dotenv => dotenv::parse(read, options.expansion.clone())?,
+        };
+        Ok(Self {
+            format,
+            options,
+            tree,
+            warnings,
+            nested_on: None,
+        })
+    }
+
+    /// Parse `input` as `format`.
+    ///
+    /// # Errors
+    ///
+    /// Fails when the data is not valid `format`.
+    pub fn from_str(input: &str, format: Format, options: ParseOptions) -> Result<Self> {
+        Self::from_reader(input.as_bytes(), format, options)
+    }
+
+    /// Parse an already opened [`Input`], detecting its format if unknown.
+    ///
+    /// Every node is tagged with the input name as its source.
+    ///
+    /// # Errors
+    ///
+    /// Fails when the data is not valid in the input's format, errors point at
+    /// where in the input it happened.
+    pub fn from_input(mut input: Input, options: ParseOptions) -> Result<Self> {
+        let options = ParseOptions {
+            delimiter: options.delimiter.or(input.delimiter),
+            ..options
+        };
+        // Read it all upfront to show the offending line on errors
+        let mut text = String::new();
+        input
+            .reader
+            .read_to_string(&mut text)
+            .map_err(|err| Error::from(err).in_source(&input.name, None))?;
+        let mut document = match input.format {
+            Some(format) => Self::from_str(&text, format, options),
+            None => Self::detect(&text, &options),
+        }
+        .map_err(|err| err.in_source(&input.name, Some(&text)))?;
+        document.tree.set_source(&Arc::from(input.name));
+        Ok(document)
+    }
+
+    /// Deep merge `other` on top of this document, `other` wins.
+    ///
+    /// Maps are merged key by key, sequences as [`Arrays`] says, anything else
+    /// is replaced. Flat .env keys are nested on the separator first, on either
+    /// side, so `db.host=...` and `{"db": {"host": ...}}` override each other.
+    ///
+    /// The merged document keeps this document's format, for match templates.
+    /// Flat .env templates are nested the same way to match it.
+    ///
+    /// # Errors
+    ///
+    /// Fails when a .env key is both a value and a prefix of another key, e.g.
+    /// `A=1` and `A_B=2` nested on `_`.
+    pub fn merge(mut self, other: Self, options: MergeOptions) -> Result<Self> {
+        let warnings = other.warnings;
+        let other = match other.format {
+            Format::Dotenv => nest_keys(other.tree, options.separator)?,
+            _ => other.tree,
+        };
+        // Only flat until merged once
+        let flat = |tree: &Node| match &tree.kind {
+            Kind::Map(map) => map
+                .values()
+                .all(|node| matches!(node.kind, Kind::Scalar(_))),
+            _ => false,
+        };
+        if self.format == Format::Dotenv && flat(&self.tree) {
+            let tree = std::mem::replace(&mut self.tree, Node::map(BTreeMap::new()));
+            self.tree = nest_keys(tree, options.separator)?;
+            self.nested_on = Some(options.separator);
+        }
+        merge(&mut self.tree, other, options.arrays);
+        self.warnings.extend(warnings);
+        Ok(self)
+    }
+
+    /// Parse `input` as the most likely format it actually parses as.
+    ///
+    /// Candidates are tried from the most likely one, each parsed at most once,
+    /// until one parses. Equally likely candidates that also parse must read the
+    /// same, otherwise the format is ambiguous.
+    ///
+    /// Candidates are parsed without running .env command substitutions, the
+    /// input may not even be .env. Once it is known to be, it is parsed again
+    /// with them.
+    ///
+    /// # Errors
+    ///
+    /// Fails when no candidate parses, with the error of the most likely one,
+    /// or when the format is ambiguous.
+    pub fn detect(input: &str, options: &ParseOptions) -> Result<Self> {
+        let guessing = &without_exec(options);
+        let mut detected: Option<(Self, usize)> = None;
+        let mut first_error = None;
+        for (format, score) in input::candidates(input) {
+            if let Some((document, best)) = &detected {
+                if score < *best {
+                    break;
+                }
+                // Only worth parsing to tell whether the tie matters
+                if let Ok(other) = Self::from_str(input, format, guessing.clone()) {
+                    if !document.reads_as(&other) {
+                        return Err(Error::InvalidInputFormat(format!(
+                            "Input reads as both {} and {format}, set it with --format",
+                            document.format
+                        )));
+                    }
+                }
+                continue;
+            }
+            match Self::from_str(input, format, guessing.clone()) {
+                Ok(document) => detected = Some((document, score)),
+                Err(err) => {
+                    first_error.get_or_insert(err);
+                }
+            }
+        }
+        match (detected, first_error) {
+            (Some((document, _)), _)
+                if document.format == Format::Dotenv && options.expansion.allow_exec.is_some() =>
+            {
+                Self::from_str(input, Format::Dotenv, options.clone())
+            }
+            (Some((document, _)), _) => Ok(Self {
+                options: options.clone(),
+                ..document
+            }),
+            (None, Some(err)) => Err(err),
+            (None, None) => Err(Error::InvalidInputFormat(
+                "Couldn't detect the input format, set it with --format".to_owned(),
+            )),
+        }
+    }
+
+    // Whether both documents flatten to the same keys and values
+    fn reads_as(&self, other: &Self) -> bool {
+        let leaves = |document: &Self| {
+            document
+                .tree
+                .leaves()
+                .into_iter()
+                .map(|(path, _, scalar)| (path, scalar.as_str().map(str::to_owned)))
+                .collect::<Vec<_>>()
+        };
+        leaves(self) == leaves(other)
+    }
+
+    /// Parse a match template written in this document's format, where `$VAR`
+    /// placeholders stand for the values to extract.
+    ///
+    /// # Errors
+    ///
+    /// Fails when the template is not valid in this document's format.
+    pub fn template(&self, template: &str) -> Result<Self> {
+        Ok(Self {
+            format: self.format,
+            options: self.options.clone(),
+            tree: parse_template(template, self.format, &self.options, self.nested_on)?,
+            warnings: Vec::new(),
+            nested_on: self.nested_on,
+        })
+    }
+
+    #[must_use]
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    /// The parsed data
+    #[must_use]
+    pub fn tree(&self) -> &Node {
+        &self.tree
+    }
+
+    /// What parsing let through but is worth telling, e.g. .env command
+    /// substitutions kept as written
+    #[must_use]
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+
+    /// Flatten the document into one entry per scalar leaf
+    #[must_use]
+    pub fn flatten(&self, options: FlattenOptions) -> Flattened {
+        let entries = self
+            .tree
+            .leaves()
+            .into_iter()
+            .map(|(path, node, scalar)| Entry {
+                key: options.key(&path),
+                path,
+                value: scalar.clone(),
+                span: node.span,
+            })
+            .collect();
+        Flattened {
+            format: self.format,
+            parse_options: self.options.clone(),
+            nested_on: self.nested_on,
+            options,
+            tree: self.tree.clone(),
+            entries,
+        }
+    }
+}
+
+fn merge(into: &mut Node, other: Node, arrays: Arrays) {
+    match (&mut into.kind, other.kind) {
+        (Kind::Map(map), Kind::Map(other)) => {
+            for (key, node) in other {
+                match map.entry(key) {
+                    btree_map::Entry::Occupied(mut entry) => merge(entry.get_mut(), node, arrays),
+                    btree_map::Entry::Vacant(entry) => {
+                        entry.insert(node);
+                    }
+                }
+            }
+        }
+        (Kind::Seq(seq), Kind::Seq(other)) if arrays != Arrays::Replace => {
+            if arrays == Arrays::Append {
+                seq.extend(other);
+                return;
+            }
+            for (idx, node) in other.into_iter().enumerate() {
+                match seq.get_mut(idx) {
+                    Some(existing) => merge(existing, node, arrays),
+                    None => seq.push(node),
+                }
+            }
+        }
+        (_, kind) => {
+            *into = Node {
+                kind,
+                span: other.span,
+                source: other.source,
+            };
+        }
+    }
+}
+
+// A flat map with its keys split on `separator` into nested maps
+fn nest_keys(tree: Node, separator: char) -> Result<Node> {
+    let Kind::Map(map) = tree.kind else {
+        return Ok(tree);
+    };
+    let mut nested = Node {
+        kind: Kind::Map(BTreeMap::new()),
+        ..tree
+    };
+    for (key, node) in map {
+        let path = key.split(separator).map(str::to_owned).collect::<Vec<_>>();
+        // Merging would replace the value with a map, or the other way around
+        if let Some(depth) = already_set(&nested, &path) {
+            return Err(Error::KeyCollision(format!(
+                "Key `{key}` can't be nested on `{separator}`, `{}` is already set",
+                path[..depth].join(&separator.to_string())
+            )));
+        }
+        merge(&mut nested, nest(path, node), Arrays::Replace);
+    }
+    Ok(nested)
+}
+
+// How far down `path` something already sits in `tree`, if anything does
+fn already_set(tree: &Node, path: &[String]) -> Option<usize> {
+    let mut node = tree;
+    for (depth, key) in path.iter().enumerate() {
+        match &node.kind {
+            Kind::Map(map) => node = map.get(key)?,
+            _ => return Some(depth),
+        }
+    }
+    Some(path.len())
+}
+
+// `node` wrapped in maps down `path`
+fn nest(path: Vec<String>, node: Node) -> Node {
+    path.into_iter().rev().fold(node, |node, key| Node {
+        span: node.span,
+        source: node.source.clone(),
+        ..Node::map(BTreeMap::from([(key, node)]))
+    })
+}
+
+// Templates and guesses are never worth running commands for
+fn without_exec(options: &ParseOptions) -> ParseOptions {
+    ParseOptions {
+        expansion: dotenv::Expansion {
+            allow_exec: None,
+            ..options.expansion.clone()
+        },
+        ..options.clone()
+    }
+}
+
+fn parse_template(
+    template: &str,
+    format: Format,
+    options: &ParseOptions,
+    nested_on: Option<char>,
+) -> Result<Node> {
+    // Bare names are only strings in xml, csv and .env
+    let quote = matches!(format, Format::Json | Format::Yaml | Format::Toml);
+    let mut fixed = Fixed::new(template, quote);
+    if format == Format::Dotenv {
+        // Items can be separated by `;` on a single line, e.g. a=$BAZ;b=$BURG
+        fixed.text = fixed.text.replace(';', "\n");
+    }
+    let options = ParseOptions {
+        // Whatever the data is delimited by, the template has its own
+        delimiter: None,
+        ..without_exec(options)
+    };
+    let tree = Document::from_str(&fixed.text, format, options)
+        .map(|document| document.tree)
+        .map_err(|err| {
+            let span = err
+                .span(&fixed.text)
+                .and_then(|span| offset_at(&fixed.text, span))
+                .and_then(|offset| span_at(template, fixed.original(offset)));
+            Error::InvalidMatchTemplate(err.message(), span).in_source("--match", Some(template))
+        })?;
+    match nested_on {
+        Some(separator) => nest_keys(tree, separator),
+        None => Ok(tree),
+    }
+}
+
+/// How documents are merged, see [`Document::merge`]
+#[derive(Debug, Clone, Copy)]
+pub struct MergeOptions {
+    pub arrays: Arrays,
+    /// Separator flat .env keys are nested on
+    pub separator: char,
+}
+
+impl Default for MergeOptions {
+    fn default() -> Self {
+        Self {
+            arrays: Arrays::default(),
+            separator: '.',
+        }
+    }
+}
+
+/// How a sequence is merged with the one it overrides
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Arrays {
+    /// The later sequence wins whole
+    #[default]
+    Replace,
+    /// The later elements go after the earlier ones
+    Append,
+    /// Elements at the same index are merged, extra ones are kept
+    MergeByIndex,
+}
+
+impl std::fmt::Display for Arrays {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Arrays::Replace => write!(f, "replace"),
+            Arrays::Append => write!(f, "append"),
+            Arrays::MergeByIndex => write!(f, "merge-by-index"),
+        }
+    }
+}
+
+/// How flattened paths become keys
+#[derive(Debug, Clone, Copy)]
+pub struct FlattenOptions {
+    /// Separator used to chain nesting keys
+    pub separator: char,
+    /// Case sensitivity for the keys
+    pub casing: Casing,
+    /// How template variables bound to a map or sequence are rendered
+    pub subtrees: Subtrees,
+}
+
+impl Default for FlattenOptions {
+    fn default() -> Self {
+        Self {
+            separator: '.',
+            casing: Casing::default(),
+            subtrees: Subtrees::default(),
+        }
+    }
+}
+
+impl FlattenOptions {
+    fn key(self, path: &[String]) -> String {
+        self.casing.apply(&path.join(&self.separator.to_string()))
+    }
+}
+
+/// A scalar leaf of a document
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    /// Segments leading to the value in the document
+    pub path: Vec<String>,
+    /// The name the value goes by, e.g. `baz.quz` or a template variable
+    pub key: String,
+    pub value: Scalar,
+    /// Where the value was found, if the parser reports positions
+    pub span: Option<Span>,
+}
+
+/// Flattened entries of a document, sorted by path
+#[derive(Debug, Clone)]
+pub struct Flattened {
+    // Needed to parse match templates
+    format: Format,
+    parse_options: ParseOptions,
+    nested_on: Option<char>,
+    options: FlattenOptions,
+    // Needed to capture subtrees
+    tree: Node,
+    entries: Vec<Entry>,
+}
+
+impl Flattened {
+    /// Select the values bound by a match template written in the document's
+    /// format, or in path form (`servers.*.host=$HOST_{}`), renamed after the
+    /// template variables.
+    ///
+    /// A variable bound to a map or sequence captures it whole, see [`Subtrees`].
+    /// Wildcards bind one variable per match, see [`Pattern::from_template`].
+    ///
+    /// Variables whose path is missing from the data are left out, unless they
+    /// have a default (`$PORT:-8080`) or are required (`$API_KEY!`).
+    ///
+    /// # Errors
+    ///
+    /// Fails when the template is not valid in the document's format nor in path
+    /// form, or with every required variable that is missing.
+    pub fn matching(self, template: &str) -> Result<Self> {
+        let parsed = parse_template(template, self.format, &self.parse_options, self.nested_on);
+        let template = match parsed {
+            Ok(template) => template,
+            Err(err) => template::from_paths(template, self.options.separator).ok_or(err)?,
+        };
+
+        let mut entries = Vec::new();
+        let mut missing = Vec::new();
+        for pattern in Pattern::from_template(&template)? {
+            let matches = pattern.matches(&self.tree, self.options.separator);
+            if matches.is_empty() {
+                match pattern.missing() {
+                    Missing::Skip => {}
+                    Missing::Default(value) => entries.push(Entry {
+                        path: pattern.path(),
+                        key: self.options.casing.apply(pattern.variable()),
+                        value: Scalar::String(value.clone()),
+                        span: None,
+                    }),
+                    Missing::Required => missing.push((
+                        pattern.variable().to_owned(),
+                        pattern.path().join(&self.options.separator.to_string()),
+                    )),
+                }
+            }
+            for found in matches {
+                self.capture(&found.variable, found.path, found.node, &mut entries);
+            }
+        }
+        if !missing.is_empty() {
+            return Err(Error::MissingRequired(missing));
+        }
+        Ok(Self { entries, ..self })
+    }
+
+    /// Select the values a JSON path query picks, see [`crate::query`], keyed
+    /// by their path as when flattened.
+    ///
+    /// Selected maps and sequences are rendered as [`Subtrees`] says.
+    ///
+    /// # Errors
+    ///
+    /// Fails when the query can't be parsed.
+    pub fn query(self, query: &str) -> Result<Self> {
+        let parsed = Query::parse(query).map_err(|err| err.in_source("--query", Some(query)))?;
+
+        let mut entries = Vec::new();
+        for found in parsed.select(&self.tree) {
+            let key = self.options.key(&found.path);
+            self.capture(&key, found.path, found.node, &mut entries);
+        }
+        Ok(Self { entries, ..self })
+    }
+
+    // Bind `variable` to the node at `path`
+    fn capture(&self, variable: &str, path: Vec<String>, node: &Node, entries: &mut Vec<Entry>) {
+        let key = self.options.casing.apply(variable);
+        match (&node.kind, self.options.subtrees) {
+            (Kind::Scalar(scalar), _) => entries.push(Entry {
+                path,
+                key,
+                value: scalar.clone(),
+                span: node.span,
+            }),
+            (_, Subtrees::Json) => entries.push(Entry {
+                path,
+                key,
+                value: Scalar::String(json::to_compact_string(node)),
+                span: node.span,
+            }),
+            (_, Subtrees::Flatten) => {
+                for (subpath, leaf, scalar) in node.leaves() {
+                    entries.push(Entry {
+                        key: format!(
+                            "{key}{}{}",
+                            self.options.separator,
+                            self.options.key(&subpath)
+                        ),
+                        path: path.iter().chain(&subpath).cloned().collect(),
+                        value: scalar.clone(),
+                        span: leaf.span,
+                    });
+                }
+            }
+        }
+    }
+
+    /// The inputs the value of `entry` was read from.
+    ///
+    /// Captured subtrees can come from several merged inputs, template
+    /// defaults from none.
+    #[must_use]
+    pub fn sources(&self, entry: &Entry) -> Vec<&str> {
+        let Some(node) = self.tree.get(&entry.path) else {
+            return Vec::new();
+        };
+        let leaves = match &node.kind {
+            Kind::Scalar(_) => vec![node],
+            _ => node.leaves().into_iter().map(|(_, leaf, _)| leaf).collect(),
+        };
+        let mut sources = Vec::new();
+        for source in leaves.into_iter().filter_map(|leaf| leaf.source.as_deref()) {
+            if !sources.contains(&source) {
+                sources.push(source);
+            }
+        }
+        sources
+    }
+
+    #[must_use]
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, Entry> {
+        self.entries.iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a Flattened {
+    type Item = &'a Entry;
+    type IntoIter = std::slice::Iter<'a, Entry>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.entries.iter()
+    }
+}
+
+impl IntoIterator for Flattened {
+    type Item = Entry;
+    type IntoIter = std::vec::IntoIter<Entry>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.entries.into_iter()
+    }
+}
+
+/// How a template variable bound to a map or sequence is rendered
+#[derive(Default, Debug, Clone, ValueEnum, Copy)]
+pub enum Subtrees {
+    /// As a single compact json value
+    #[default]
+    Json,
+    /// As one entry per leaf, keyed by the variable and the path below it
+    Flatten,
+}
+
+impl std::fmt::Display for Subtrees {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Subtrees::Json => write!(f, "json"),
+            Subtrees::Flatten => write!(f, "flatten"),
+        }
+    }
+}
+
+#[derive(Default, Debug, Clone, ValueEnum, Copy)]
+pub enum Casing {
+    #[default]
+    Insensitive,
+    Lower,
+    Upper,
+}
+
+impl Casing {
+    #[must_use]
+    pub fn apply(self, value: &str) -> String {
+        match self {
+            Casing::Insensitive => value.to_owned(),
+            Casing::Lower => value.to_lowercase(),
+            Casing::Upper => value.to_uppercase(),
+        }
+    }
+}
+
+impl std::fmt::Display for Casing {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Casing::Insensitive => write!(f, "insensitive"),
+            Casing::Lower => write!(f, "lower"),
+            Casing::Upper => write!(f, "upper"),
+        }
+    }
+}
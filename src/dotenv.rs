@@ -3,34 +3,205 @@
 use std::collections::BTreeMap;
 use std::io::prelude::*;
 use std::io::{BufReader, Lines};
+use std::path::Path;
+use std::time::Duration;
 
-use regex::Regex;
+use clap::ValueEnum;
 
+use crate::document::ParseOptions;
 use crate::error::{Error, Result};
+use crate::input::{Format, Input};
+use crate::tree::{Kind, Node, Scalar, Span};
+use crate::Document;
 
 pub type Map = BTreeMap<String, String>;
 
-pub fn into_fixed(value: &str) -> String {
-    let re = Regex::new(r"\$\s*\b(\w+)\b").unwrap();
-    let corrected_str = re.replace_all(value, |caps: &regex::Captures| caps[1].to_string());
-    // Replace semicolons with newlines
-    corrected_str.replace(';', "\n")
+/// What `$NAME` and `${NAME}` expand to when `NAME` is neither in the
+/// environment nor set by an earlier line
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum UnsetVars {
+    /// An empty string
+    #[default]
+    Empty,
+    /// The expansion as written, e.g. `${NAME}`
+    Keep,
+    /// An error
+    Error,
 }
 
+impl std::fmt::Display for UnsetVars {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UnsetVars::Empty => write!(f, "empty"),
+            UnsetVars::Keep => write!(f, "keep"),
+            UnsetVars::Error => write!(f, "error"),
+        }
+    }
+}
+
+/// The environment expansions look variables up in, besides earlier lines
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub enum EnvVars {
+    /// The process environment
+    #[default]
+    Process,
+    /// Only these, e.g. none for reproducible results
+    Only(Map),
+}
+
+impl EnvVars {
+    /// The variables set by .env files, later files override earlier ones.
+    ///
+    /// Each file expands with the variables of the files before it only.
+    ///
+    /// # Errors
+    ///
+    /// Fails when a file can't be read or parsed.
+    pub fn from_files(paths: &[impl AsRef<Path>]) -> Result<Self> {
+        let mut vars = Map::new();
+        for path in paths {
+            let options = ParseOptions {
+                expansion: Expansion {
+                    env: EnvVars::Only(vars.clone()),
+                    ..Expansion::default()
+                },
+                ..ParseOptions::default()
+            };
+            let input = Input::from_path(path, Some(Format::Dotenv))?;
+            let document = Document::from_input(input, options)?;
+            if let Kind::Map(map) = &document.tree().kind {
+                for (key, node) in map {
+                    if let Kind::Scalar(scalar) = &node.kind {
+                        vars.insert(key.clone(), scalar.as_str().unwrap_or_default().to_owned());
+                    }
+                }
+            }
+        }
+        Ok(EnvVars::Only(vars))
+    }
+}
+
+/// Which of the environment and earlier lines wins when both set a variable
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum EnvPrecedence {
+    /// The file's own earlier lines
+    File,
+    /// The environment
+    #[default]
+    Process,
+}
+
+impl std::fmt::Display for EnvPrecedence {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EnvPrecedence::File => write!(f, "file"),
+            EnvPrecedence::Process => write!(f, "process"),
+        }
+    }
+}
+
+/// How .env values expand variables
+#[derive(Default, Debug, Clone)]
+pub struct Expansion {
+    pub unset_vars: UnsetVars,
+    pub env: EnvVars,
+    pub precedence: EnvPrecedence,
+    /// How long `$(command)` and `` `command` `` substitutions may run, `None`
+    /// to keep them as written with a warning
+    pub allow_exec: Option<Duration>,
+}
+
+/// Serialize flat pairs as .env lines that `from_reader` reads back verbatim
+#[must_use]
+pub fn to_string(pairs: &[(String, Scalar)]) -> String {
+    let mut output = String::new();
+    for (key, value) in pairs {
+        let value = value.as_str().unwrap_or("null");
+        output.push_str(key);
+        output.push('=');
+        if value.contains(['\'', '\n']) {
+            // Weak quotes support escapes
+            output.push('"');
+            for c in value.chars() {
+                match c {
+                    '\\' | '"' | '$' => {
+                        output.push('\\');
+                        output.push(c);
+                    }
+                    '\n' => output.push_str(r"\n"),
+                    c => output.push(c),
+                }
+            }
+            output.push('"');
+        } else {
+            // Strong quotes take everything literally
+            output.push('\'');
+            output.push_str(value);
+            output.push('\'');
+        }
+        output.push('\n');
+    }
+    output
+}
+
+/// Lazily parse .env lines, see [`from_reader`]
+///
+/// # Errors
+///
+/// Never, lines are only parsed when iterating.
 #[allow(clippy::unnecessary_wraps)]
 pub fn from_str(input: &str) -> Result<parser::Iter<&[u8]>> {
     Ok(parser::Iter::new(input.as_bytes()))
 }
 
+/// Lazily parse .env lines into `(key, value)` pairs, expanding `$VAR`s.
+///
+/// Expansions follow POSIX parameter expansion: `$NAME`, `${NAME}`,
+/// `${NAME:-default}`, `${NAME-default}`, `${NAME:?message}`, `${NAME?message}`,
+/// `${NAME:+alternative}` and `${NAME+alternative}`, nested like `${A:-${B}}`.
 pub fn from_reader(read: impl Read) -> parser::Iter<impl Read> {
     parser::Iter::new(read)
 }
 
+/// Parse .env lines into a flat map of strings, later keys override earlier ones,
+/// along with warnings about command substitutions kept as written
+///
+/// # Errors
+///
+/// Fails on the first line that can't be parsed, or whose expansion requires
+/// an unset variable.
+pub fn parse(read: impl Read, expansion: Expansion) -> Result<(Node, Vec<String>)> {
+    let mut map = BTreeMap::new();
+    let mut lines = from_reader(read).expansion(expansion);
+    while let Some(line) = lines.next() {
+        let (key, value) = line?;
+        map.insert(
+            key,
+            Node::scalar(Scalar::String(value)).with_span(lines.span()),
+        );
+    }
+    Ok((Node::map(map), lines.warnings().to_vec()))
+}
+
 mod parser {
-    use super::{BTreeMap, BufRead, BufReader, Error, Lines, Read, Result};
+    use std::cell::RefCell;
+    use std::os::unix::process::CommandExt;
+    use std::process::{Child, Command, Stdio};
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use super::{
+        BTreeMap, BufRead, BufReader, EnvPrecedence, EnvVars, Error, Expansion, Lines, Read,
+        Result, Span, UnsetVars,
+    };
     pub struct Iter<R: Read> {
         lines: Lines<BufReader<R>>,
         substitution_data: BTreeMap<String, Option<String>>,
+        expansion: Expansion,
+        line: usize,
+        span: Span,
+        warnings: Vec<String>,
     }
 
     impl<R: Read> Iter<R> {
@@ -38,8 +209,28 @@ mod parser {
             Iter {
                 lines: BufReader::new(read).lines(),
                 substitution_data: BTreeMap::new(),
+                expansion: Expansion::default(),
+                line: 0,
+                span: Span { line: 0, column: 0 },
+                warnings: Vec::new(),
             }
         }
+
+        /// Set how values expand variables
+        #[must_use]
+        pub fn expansion(self, expansion: Expansion) -> Self {
+            Self { expansion, ..self }
+        }
+
+        /// Where the value of the last parsed pair starts
+        pub fn span(&self) -> Span {
+            self.span
+        }
+
+        /// Command substitutions kept as written so far, and where
+        pub fn warnings(&self) -> &[String] {
+            &self.warnings
+        }
     }
 
     impl<R: Read> Iterator for Iter<R> {
@@ -47,60 +238,111 @@ mod parser {
 
         fn next(&mut self) -> Option<Self::Item> {
             loop {
-                let line = match self.lines.next() {
+                let mut text = match self.lines.next() {
                     Some(Ok(line)) => line,
                     Some(Err(err)) => return Some(Err(Error::Io(err))),
                     None => return None,
                 };
-
-                match parse_line(&line, &mut self.substitution_data) {
-                    Ok(Some(result)) => return Some(Ok(result)),
-                    Ok(None) => {}
-                    Err(err) => return Some(Err(err)),
+                self.line += 1;
+                let number = self.line;
+
+                let context = Context::new(&self.substitution_data, &self.expansion);
+                // Each attempt goes on from where the last one stopped
+                let mut scanned = Scan::default();
+                loop {
+                    match parse_line(&text, number, &context, &mut scanned) {
+                        Ok(Parsed::Pair(key, value, span)) => {
+                            self.span = span;
+                            for command in context.skipped.borrow_mut().drain(..) {
+                                self.warnings.push(format!(
+                                    "{command} at {span} is kept as is, pass --allow-exec to run it"
+                                ));
+                            }
+                            // Later lines can expand it
+                            self.substitution_data.insert(key.clone(), value.clone());
+                            return Some(Ok((key, value.unwrap_or_default())));
+                        }
+                        Ok(Parsed::Blank) => break,
+                        // Quoted values and `\` continuations go on on the next line
+                        Ok(Parsed::Unfinished(err)) => loop {
+                            match self.lines.next() {
+                                Some(Ok(line)) => {
+                                    self.line += 1;
+                                    text.push('\n');
+                                    text.push_str(&line);
+                                    if scanned.closes(&line) {
+                                        break;
+                                    }
+                                }
+                                Some(Err(err)) => return Some(Err(Error::Io(err))),
+                                None => return Some(Err(err)),
+                            }
+                        },
+                        Err(err) => return Some(Err(err)),
+                    }
                 }
             }
         }
     }
 
-    type ParsedLine = Result<Option<(String, String)>>;
+    enum Parsed {
+        /// The key, its value and where the value starts
+        Pair(String, Option<String>, Span),
+        /// Empty or a comment
+        Blank,
+        /// The value goes on past the end of the text, the error if it doesn't
+        Unfinished(Error),
+    }
+
+    type ParsedLine = Result<Parsed>;
 
-    fn parse_line(
-        line: &str,
-        substitution_data: &mut BTreeMap<String, Option<String>>,
-    ) -> ParsedLine {
-        let mut parser = LineParser::new(line, substitution_data);
-        parser.parse_line()
+    fn parse_line(line: &str, number: usize, context: &Context, scan: &mut Scan) -> ParsedLine {
+        let mut parser = LineParser::new(line, number, context);
+        parser.parse_line(scan)
     }
 
     struct LineParser<'a> {
         original_line: &'a str,
-        substitution_data: &'a mut BTreeMap<String, Option<String>>,
+        number: usize,
+        context: &'a Context<'a>,
         line: &'a str,
         pos: usize,
     }
 
     impl<'a> LineParser<'a> {
-        fn new(
-            line: &'a str,
-            substitution_data: &'a mut BTreeMap<String, Option<String>>,
-        ) -> LineParser<'a> {
+        fn new(line: &'a str, number: usize, context: &'a Context<'a>) -> LineParser<'a> {
             LineParser {
                 original_line: line,
-                substitution_data,
+                number,
+                context,
                 line: line.trim_end(), // we don’t want trailing whitespace
                 pos: 0,
             }
         }
 
+        fn span_at(&self, pos: usize) -> Span {
+            // Values can span several lines
+            let before = &self.original_line[..pos];
+            let line_start = before.rfind('\n').map_or(0, |idx| idx + 1);
+            Span {
+                line: self.number + before.matches('\n').count(),
+                column: before[line_start..].chars().count() + 1,
+            }
+        }
+
         fn err(&self) -> Error {
-            Error::LineParse(self.original_line.into(), self.pos)
+            self.err_at(self.pos)
+        }
+
+        fn err_at(&self, pos: usize) -> Error {
+            Error::LineParse(self.span_at(pos))
         }
 
-        fn parse_line(&mut self) -> ParsedLine {
+        fn parse_line(&mut self, scan: &mut Scan) -> ParsedLine {
             self.skip_whitespace();
             // if its an empty line or a comment, skip it
             if self.line.is_empty() || self.line.starts_with('#') {
-                return Ok(None);
+                return Ok(Parsed::Blank);
             }
 
             let mut key = self.parse_key()?;
@@ -120,15 +362,23 @@ mod parser {
             self.skip_whitespace();
 
             if self.line.is_empty() || self.line.starts_with('#') {
-                self.substitution_data.insert(key.clone(), None);
-                return Ok(Some((key, String::new())));
+                return Ok(Parsed::Pair(key, None, self.span_at(self.pos)));
             }
 
-            let parsed_value = parse_value(self.line, self.substitution_data)?;
-            self.substitution_data
-                .insert(key.clone(), Some(parsed_value.clone()));
-
-            Ok(Some((key, parsed_value)))
+            match parse_value(self.line, self.context, scan) {
+                Ok(value) => Ok(Parsed::Pair(key, Some(value), self.span_at(self.pos))),
+                Err(Fail::Syntax(offset)) => Err(self.err_at(self.pos + offset)),
+                Err(Fail::Unfinished(offset)) => {
+                    Ok(Parsed::Unfinished(self.err_at(self.pos + offset)))
+                }
+                Err(Fail::Unset(offset, message)) => Err(Error::UnsetVariable(
+                    message,
+                    self.span_at(self.pos + offset),
+                )),
+                Err(Fail::Command(offset, message)) => {
+                    Err(Error::Command(message, self.span_at(self.pos + offset)))
+                }
+            }
         }
 
         fn parse_key(&mut self) -> Result<String> {
@@ -171,153 +421,380 @@ mod parser {
         }
     }
 
-    #[derive(Eq, PartialEq)]
-    enum SubstitutionMode {
-        None,
-        Block,
-        EscapedBlock,
+    /// Why a value can't be parsed, with the byte offset of where
+    pub enum Fail {
+        Syntax(usize),
+        /// A quote or a `\` continuation is still open at the end
+        Unfinished(usize),
+        /// A variable that must be set isn't, and the message saying so
+        Unset(usize, String),
+        /// A command substitution failed, and why
+        Command(usize, String),
+    }
+
+    impl Fail {
+        fn shift(self, by: usize) -> Self {
+            match self {
+                Fail::Syntax(offset) => Fail::Syntax(offset + by),
+                Fail::Unfinished(offset) => Fail::Unfinished(offset + by),
+                Fail::Unset(offset, message) => Fail::Unset(offset + by, message),
+                Fail::Command(offset, message) => Fail::Command(offset + by, message),
+            }
+        }
+    }
+
+    /// How far [`parse_value`] got through a value that goes on on the next line
+    #[derive(Default)]
+    struct Scan {
+        output: String,
+        strong_quote: bool,
+        weak_quote: bool,
+        escaped: bool,
+        /// Byte offset to go on from
+        resume_at: usize,
+        /// The last opening quote or `\` outside quotes, where an unfinished value starts
+        opened_at: usize,
+        /// What closes the backtick or `$(` left open, which is scanned again whole
+        closer: Option<char>,
+    }
+
+    impl Scan {
+        /// Whether `line` is worth scanning the value again for
+        fn closes(&self, line: &str) -> bool {
+            self.closer.is_none_or(|closer| line.contains(closer))
+        }
+
+        /// Left open by the backtick or `$(` at `index`, until `closer`
+        fn unfinished_at(&mut self, index: usize, closer: char) -> Fail {
+            self.resume_at = index;
+            self.closer = Some(closer);
+            Fail::Unfinished(index)
+        }
     }
 
-    #[allow(clippy::too_many_lines)]
+    /// Unquote, unescape and expand the value `input` starts with.
+    ///
+    /// An unfinished value leaves `scan` where it stopped, to go on from once
+    /// the next line is appended to `input`.
     fn parse_value(
         input: &str,
-        substitution_data: &mut BTreeMap<String, Option<String>>,
-    ) -> Result<String> {
-        let mut strong_quote = false;
-        let mut weak_quote = false;
-        let mut escaped = false;
+        context: &Context,
+        scan: &mut Scan,
+    ) -> std::result::Result<String, Fail> {
+        let start = scan.resume_at;
+        scan.closer = None;
         let mut expecting_end = false;
+        // End of the last expansion, which was consumed whole
+        let mut expanded_until = start;
 
-        let mut output = String::new();
-
-        let mut substitution_mode = SubstitutionMode::None;
-        let mut substitution_name = String::new();
-
-        for (index, c) in input.chars().enumerate() {
+        for (index, c) in input[start..].char_indices() {
+            let index = start + index;
+            if index < expanded_until {
+                continue;
+            }
             if expecting_end {
                 if c == ' ' || c == '\t' {
                     continue;
                 } else if c == '#' {
                     break;
                 }
-                return Err(Error::LineParse(input.to_owned(), index));
-            } else if escaped {
+                return Err(Fail::Syntax(index));
+            } else if scan.escaped {
                 match c {
-                    '\\' | '\'' | '"' | '$' | ' ' => output.push(c),
-                    'n' => output.push('\n'),
+                    '\\' | '\'' | '"' | '$' | '`' | ' ' => scan.output.push(c),
+                    'n' => scan.output.push('\n'),
+                    // A line continuation
+                    '\n' => {}
                     _ => {
-                        return Err(Error::LineParse(input.to_owned(), index));
+                        return Err(Fail::Syntax(index));
                     }
                 }
 
-                escaped = false;
-            } else if strong_quote {
+                scan.escaped = false;
+            } else if scan.strong_quote {
                 if c == '\'' {
-                    strong_quote = false;
-                } else {
-                    output.push(c);
-                }
-            } else if substitution_mode != SubstitutionMode::None {
-                if c.is_alphanumeric() {
-                    substitution_name.push(c);
+                    scan.strong_quote = false;
                 } else {
-                    match substitution_mode {
-                        SubstitutionMode::None => unreachable!(),
-                        SubstitutionMode::Block => {
-                            if c == '{' && substitution_name.is_empty() {
-                                substitution_mode = SubstitutionMode::EscapedBlock;
-                            } else {
-                                apply_substitution(
-                                    substitution_data,
-                                    &substitution_name.drain(..).collect::<String>(),
-                                    &mut output,
-                                );
-                                if c == '$' {
-                                    substitution_mode = if !strong_quote && !escaped {
-                                        SubstitutionMode::Block
-                                    } else {
-                                        SubstitutionMode::None
-                                    }
-                                } else {
-                                    substitution_mode = SubstitutionMode::None;
-                                    output.push(c);
-                                }
-                            }
-                        }
-                        SubstitutionMode::EscapedBlock => {
-                            if c == '}' {
-                                substitution_mode = SubstitutionMode::None;
-                                apply_substitution(
-                                    substitution_data,
-                                    &substitution_name.drain(..).collect::<String>(),
-                                    &mut output,
-                                );
-                            } else {
-                                substitution_name.push(c);
-                            }
-                        }
-                    }
+                    scan.output.push(c);
                 }
             } else if c == '$' {
-                substitution_mode = if !strong_quote && !escaped {
-                    SubstitutionMode::Block
-                } else {
-                    SubstitutionMode::None
-                }
-            } else if weak_quote {
+                let (expanded, len) = match context.expand(&input[index..]) {
+                    Ok(expanded) => expanded,
+                    Err(Fail::Unfinished(_)) => return Err(scan.unfinished_at(index, ')')),
+                    Err(fail) => return Err(fail.shift(index)),
+                };
+                scan.output.push_str(&expanded);
+                expanded_until = index + len;
+            } else if c == '`' {
+                let Some(len) = input[index + 1..].find('`') else {
+                    return Err(scan.unfinished_at(index, '`'));
+                };
+                let end = index + 1 + len;
+                let substituted = context
+                    .substitute(&input[index + 1..end], &input[index..=end])
+                    .map_err(|fail| fail.shift(index))?;
+                scan.output.push_str(&substituted);
+                expanded_until = end + 1;
+            } else if scan.weak_quote {
                 if c == '"' {
-                    weak_quote = false;
+                    scan.weak_quote = false;
                 } else if c == '\\' {
-                    escaped = true;
+                    scan.escaped = true;
                 } else {
-                    output.push(c);
+                    scan.output.push(c);
                 }
             } else if c == '\'' {
-                strong_quote = true;
+                scan.strong_quote = true;
+                scan.opened_at = index;
             } else if c == '"' {
-                weak_quote = true;
+                scan.weak_quote = true;
+                scan.opened_at = index;
             } else if c == '\\' {
-                escaped = true;
+                scan.escaped = true;
+                scan.opened_at = index;
             } else if c == ' ' || c == '\t' {
                 expecting_end = true;
             } else {
-                output.push(c);
+                scan.output.push(c);
             }
         }
 
-        if substitution_mode == SubstitutionMode::EscapedBlock || strong_quote || weak_quote {
-            let value_length = input.len();
-            Err(Error::LineParse(
-                input.to_owned(),
-                if value_length == 0 {
-                    0
-                } else {
-                    value_length - 1
-                },
-            ))
+        if scan.strong_quote || scan.weak_quote || scan.escaped {
+            scan.resume_at = input.len();
+            Err(Fail::Unfinished(scan.opened_at))
         } else {
-            apply_substitution(
+            Ok(std::mem::take(&mut scan.output))
+        }
+    }
+
+    /// What expansions of a line can see
+    struct Context<'a> {
+        substitution_data: &'a BTreeMap<String, Option<String>>,
+        expansion: &'a Expansion,
+        /// Command substitutions kept as written, to warn about
+        skipped: RefCell<Vec<String>>,
+    }
+
+    impl<'a> Context<'a> {
+        fn new(
+            substitution_data: &'a BTreeMap<String, Option<String>>,
+            expansion: &'a Expansion,
+        ) -> Self {
+            Self {
                 substitution_data,
-                &substitution_name.drain(..).collect::<String>(),
-                &mut output,
-            );
-            Ok(output)
+                expansion,
+                skipped: RefCell::default(),
+            }
         }
     }
 
-    fn apply_substitution(
-        substitution_data: &mut BTreeMap<String, Option<String>>,
-        substitution_name: &str,
-        output: &mut String,
-    ) {
-        if let Ok(environment_value) = std::env::var(substitution_name) {
-            output.push_str(&environment_value);
-        } else {
-            let stored_value = substitution_data
-                .get(substitution_name)
-                .unwrap_or(&None)
-                .clone();
-            output.push_str(&stored_value.unwrap_or_default());
+    /// Index of the `close` matching the `open` at byte 1 of `input`, e.g. in `${A:-${B}}`
+    fn closing(input: &str, open: char, close: char) -> Option<usize> {
+        let mut depth = 0;
+        input
+            .char_indices()
+            .skip(1)
+            .find(|&(_, c)| {
+                if c == open {
+                    depth += 1;
+                } else if c == close {
+                    depth -= 1;
+                }
+                depth == 0
+            })
+            .map(|(index, _)| index)
+    }
+
+    /// Run `command` with `sh`, its stdout without trailing newlines
+    fn run(command: &str, timeout: Duration) -> std::result::Result<String, String> {
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            // Its own group, so whatever it starts can be killed along with it
+            .process_group(0)
+            .spawn()
+            .map_err(|err| format!("`{command}` can't be run: {err}"))?;
+        // Read while it runs, so a full pipe doesn't block it
+        let (sender, output) = mpsc::channel();
+        if let Some(mut stdout) = child.stdout.take() {
+            thread::spawn(move || {
+                let mut output = String::new();
+                let _ = sender.send(stdout.read_to_string(&mut output).map(|_| output));
+            });
+        }
+        let timed_out = |child: &mut Child| {
+            kill_group(child);
+            format!("`{command}` timed out after {}s", timeout.as_secs_f64())
+        };
+
+        let deadline = Instant::now() + timeout;
+        let status = loop {
+            match child.try_wait() {
+                Ok(Some(status)) => break status,
+                Ok(None) if Instant::now() < deadline => thread::sleep(Duration::from_millis(10)),
+                Ok(None) => return Err(timed_out(&mut child)),
+                Err(err) => return Err(format!("`{command}` can't be waited for: {err}")),
+            }
+        };
+        if !status.success() {
+            return Err(format!("`{command}` failed with {status}"));
+        }
+        // Whatever `sh` left running in the background may still hold stdout open
+        let output = match output.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(Ok(output)) => output,
+            Ok(Err(err)) => return Err(format!("`{command}` output can't be read: {err}")),
+            Err(mpsc::RecvTimeoutError::Timeout) => return Err(timed_out(&mut child)),
+            Err(mpsc::RecvTimeoutError::Disconnected) => String::new(),
         };
+        Ok(output.trim_end_matches('\n').to_owned())
+    }
+
+    /// Kill `sh` and everything it started
+    fn kill_group(child: &mut Child) {
+        // Best effort, it may have just exited
+        if let Ok(pid) = libc::pid_t::try_from(child.id()) {
+            // SAFETY: only sends a signal, to the group `sh` leads
+            unsafe { libc::kill(-pid, libc::SIGKILL) };
+        }
+        let _ = child.kill();
+        let _ = child.wait();
+    }
+
+    // `${NAME<op><word>}` operators, longest first
+    const OPERATORS: [&str; 6] = [":-", ":?", ":+", "-", "?", "+"];
+
+    impl Context<'_> {
+        /// From the environment or earlier lines, `None` when unset
+        fn lookup(&self, name: &str) -> Option<String> {
+            let env = || match &self.expansion.env {
+                EnvVars::Process => std::env::var(name).ok(),
+                EnvVars::Only(vars) => vars.get(name).cloned(),
+            };
+            let file = || {
+                self.substitution_data
+                    .get(name)
+                    .map(|value| value.clone().unwrap_or_default())
+            };
+            match self.expansion.precedence {
+                EnvPrecedence::Process => env().or_else(file),
+                EnvPrecedence::File => file().or_else(env),
+            }
+        }
+
+        /// Expand the `$NAME` or `${...}` `input` starts with, along with how
+        /// many bytes it took
+        fn expand(&self, input: &str) -> std::result::Result<(String, usize), Fail> {
+            let name_len = |text: &str| {
+                text.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .unwrap_or(text.len())
+            };
+            if input[1..].starts_with('(') {
+                let end = closing(input, '(', ')').ok_or(Fail::Unfinished(0))?;
+                let substituted = self.substitute(&input[2..end], &input[..=end])?;
+                return Ok((substituted, end + 1));
+            }
+            if !input[1..].starts_with('{') {
+                let len = 1 + name_len(&input[1..]);
+                // A lone `$` is kept as is
+                if len == 1 {
+                    return Ok(("$".to_owned(), 1));
+                }
+                let expanded = match self.lookup(&input[1..len]) {
+                    Some(value) => value,
+                    None => self.unknown(&input[1..len], &input[..len])?,
+                };
+                return Ok((expanded, len));
+            }
+
+            let end = closing(input, '{', '}').ok_or_else(|| {
+                Fail::Syntax(input.char_indices().last().map_or(0, |(index, _)| index))
+            })?;
+            let body = &input[2..end];
+            let name = &body[..name_len(body)];
+            if name.is_empty() {
+                return Err(Fail::Syntax(2));
+            }
+            let rest = &body[name.len()..];
+            let Some(operator) = OPERATORS.into_iter().find(|op| rest.starts_with(op)) else {
+                if !rest.is_empty() {
+                    return Err(Fail::Syntax(2 + name.len()));
+                }
+                let expanded = match self.lookup(name) {
+                    Some(value) => value,
+                    None => self.unknown(name, &input[..=end])?,
+                };
+                return Ok((expanded, end + 1));
+            };
+
+            let word_start = 2 + name.len() + operator.len();
+            // Only expanded when used, like a shell does
+            let word = || {
+                self.expand_word(&input[word_start..end])
+                    .map_err(|fail| fail.shift(word_start))
+            };
+            let value = self.lookup(name);
+            // `:` makes empty values count as unset
+            let set = value
+                .as_ref()
+                .is_some_and(|value| !operator.starts_with(':') || !value.is_empty());
+            let expanded = match (operator.trim_start_matches(':'), value) {
+                ("-", Some(value)) if set => value,
+                ("-", _) => word()?,
+                ("?", Some(value)) if set => value,
+                ("?", _) => {
+                    let message = word()?;
+                    return Err(Fail::Unset(
+                        0,
+                        if message.is_empty() {
+                            format!("`{name}` is not set")
+                        } else {
+                            format!("`{name}` is not set: {message}")
+                        },
+                    ));
+                }
+                (_, _) if set => word()?,
+                (_, _) => String::new(),
+            };
+            Ok((expanded, end + 1))
+        }
+
+        /// The output of `command` when allowed to run it, `literal` being how
+        /// it was written
+        fn substitute(&self, command: &str, literal: &str) -> std::result::Result<String, Fail> {
+            let Some(timeout) = self.expansion.allow_exec else {
+                self.skipped.borrow_mut().push(literal.to_owned());
+                return Ok(literal.to_owned());
+            };
+            run(command, timeout).map_err(|message| Fail::Command(0, message))
+        }
+
+        /// Expand every `$` of `word`, the rest is taken literally
+        fn expand_word(&self, word: &str) -> std::result::Result<String, Fail> {
+            let mut output = String::new();
+            let mut rest = word;
+            while let Some(index) = rest.find('$') {
+                output.push_str(&rest[..index]);
+                let offset = word.len() - rest.len() + index;
+                let (expanded, len) = self
+                    .expand(&rest[index..])
+                    .map_err(|fail| fail.shift(offset))?;
+                output.push_str(&expanded);
+                rest = &rest[index + len..];
+            }
+            output.push_str(rest);
+            Ok(output)
+        }
+
+        /// What an unset variable with no operator expands to, `literal` being
+        /// how it was written
+        fn unknown(&self, name: &str, literal: &str) -> std::result::Result<String, Fail> {
+            match self.expansion.unset_vars {
+                UnsetVars::Empty => Ok(String::new()),
+                UnsetVars::Keep => Ok(literal.to_owned()),
+                UnsetVars::Error => Err(Fail::Unset(0, format!("`{name}` is not set"))),
+            }
+        }
     }
 }
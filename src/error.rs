@@ -1,13 +1,274 @@
+use std::fmt;
 use std::io;
+use std::sync::LazyLock;
+
+use regex::Regex;
+
+use crate::tree::Span;
 
 #[derive(Debug)]
 pub enum Error {
     NotAFile,
     Io(io::Error),
     Json(serde_json::Error),
-    LineParse(String, usize),
-    InvalidMatchTemplate(String),
+    Yaml(serde_yaml::Error),
+    Toml(toml_edit::TomlError),
+    TomlSerialize(toml::ser::Error),
+    /// The output can't be represented in the `--to` format
+    Unconvertible(String),
+    Xml(roxmltree::Error),
+    Csv(csv::Error),
+    LineParse(Span),
+    /// What is wrong with the `--match` template, and where when known
+    InvalidMatchTemplate(String, Option<Span>),
+    /// Required template variables along with their missing path
+    MissingRequired(Vec<(String, String)>),
+    /// A .env expansion requires a variable that is not set
+    UnsetVariable(String, Span),
+    /// A .env command substitution failed or timed out
+    Command(String, Span),
+    /// What was expected, and where in the query
+    InvalidQuery(String, Span),
     InvalidInputFormat(String),
+    KeyCollision(String),
+    InvalidKey(String),
+    /// `--explain` was given a key no input sets
+    UnknownKey(String),
+    InvalidSpawnKey(String),
+    UnsupportedNativeTarget(String),
+    /// Any of the above, along with the source it happened in
+    Source(Box<SourceError>),
+}
+
+/// An error located in a file, stdin or a template
+#[derive(Debug)]
+pub struct SourceError {
+    /// File path, `<stdin>` or `--match`
+    pub name: String,
+    pub span: Option<Span>,
+    /// The offending line
+    pub snippet: Option<String>,
+    pub error: Error,
+}
+
+// Parsers put their own location in the message, we render ours instead
+static TRAILING_LOCATION: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r" at (line \d+ column \d+|\d+:\d+$)").unwrap());
+static CSV_PREFIX: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"^CSV (parse |deserialize )?error: (record \d+ \([^)]*\): )?").unwrap()
+});
+
+impl Error {
+    /// Attach the name of the source the error happened in, and the offending
+    /// line of `text` when the error knows where it is.
+    #[must_use]
+    pub fn in_source(self, name: &str, text: Option<&str>) -> Self {
+        if let Error::Source(_) = self {
+            return self;
+        }
+        let span = self.span(text.unwrap_or_default());
+        let snippet = span
+            .zip(text)
+            .and_then(|(span, text)| text.lines().nth(span.line.checked_sub(1)?))
+            .map(str::to_owned);
+        Error::Source(Box::new(SourceError {
+            name: name.to_owned(),
+            span,
+            snippet,
+            error: self,
+        }))
+    }
+
+    /// Process exit code for this kind of error
+    #[must_use]
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            // Same as clap for invalid arguments
+            Error::InvalidMatchTemplate(..)
+            | Error::InvalidQuery(..)
+            | Error::UnsupportedNativeTarget(_) => 2,
+            Error::NotAFile | Error::Io(_) => 3,
+            Error::Json(_)
+            | Error::Yaml(_)
+            | Error::Toml(_)
+            | Error::Xml(_)
+            | Error::Csv(_)
+            | Error::LineParse(_)
+            | Error::InvalidInputFormat(_) => 4,
+            Error::KeyCollision(_)
+            | Error::InvalidKey(_)
+            | Error::UnknownKey(_)
+            | Error::InvalidSpawnKey(_) => 5,
+            Error::TomlSerialize(_) | Error::Unconvertible(_) => 6,
+            Error::MissingRequired(_) | Error::UnsetVariable(..) => 7,
+            Error::Command(..) => 8,
+            Error::Source(source) => source.error.exit_code(),
+        }
+    }
+
+    /// Where in `text` the error happened, when the parser says so
+    pub(crate) fn span(&self, text: &str) -> Option<Span> {
+        match self {
+            Error::Json(err) if err.line() > 0 => Some(Span {
+                line: err.line(),
+                column: err.column().max(1),
+            }),
+            Error::Yaml(err) => err.location().map(|location| Span {
+                line: location.line(),
+                column: location.column(),
+            }),
+            Error::Toml(err) => span_at(text, err.span()?.start),
+            Error::Xml(err) => Some(Span {
+                line: err.pos().row as usize,
+                column: err.pos().col as usize,
+            }),
+            Error::Csv(err) => {
+                let position = err.position()?;
+                let byte = usize::try_from(position.byte()).ok()?;
+                span_at(text, byte).or(Some(Span {
+                    line: usize::try_from(position.line()).ok()?,
+                    column: 1,
+                }))
+            }
+            Error::LineParse(span)
+            | Error::InvalidQuery(_, span)
+            | Error::UnsetVariable(_, span)
+            | Error::Command(_, span) => Some(*span),
+            Error::InvalidMatchTemplate(_, span) => *span,
+            Error::Source(source) => source.span,
+            _ => None,
+        }
+    }
+
+    /// What went wrong, without where
+    pub(crate) fn message(&self) -> String {
+        let strip = |message: String| TRAILING_LOCATION.replace_all(&message, "").into_owned();
+        match self {
+            Error::NotAFile => "Not a file".to_owned(),
+            Error::Io(err) => err.to_string(),
+            Error::Json(err) => format!("Invalid json: {}", strip(err.to_string())),
+            Error::Yaml(err) => format!("Invalid yaml: {}", strip(err.to_string())),
+            Error::Toml(err) => format!(
+                "Invalid toml: {}",
+                err.message().trim_end().replace('\n', ", ")
+            ),
+            Error::TomlSerialize(err) => format!("Can't convert to toml: {err}"),
+            Error::Xml(err) => format!("Invalid xml: {}", strip(err.to_string())),
+            Error::Csv(err) => format!("Invalid csv: {}", CSV_PREFIX.replace(&err.to_string(), "")),
+            Error::LineParse(_) => "Invalid .env line".to_owned(),
+            Error::MissingRequired(missing) => {
+                let missing = missing
+                    .iter()
+                    .map(|(variable, path)| format!("`{variable}` at `{path}`"))
+                    .collect::<Vec<_>>();
+                format!("Required values are missing: {}", missing.join(", "))
+            }
+            Error::InvalidQuery(expected, _) => format!("Invalid query, {expected}"),
+            Error::InvalidInputFormat(message)
+            | Error::InvalidMatchTemplate(message, _)
+            | Error::Unconvertible(message)
+            | Error::KeyCollision(message)
+            | Error::UnsetVariable(message, _)
+            | Error::Command(message, _) => message.clone(),
+            Error::InvalidKey(key) => format!("`{key}` is not a valid variable name"),
+            Error::UnknownKey(key) => format!("`{key}` is not set by any input"),
+            Error::InvalidSpawnKey(key) => {
+                format!("`{key}` can't be spawned, it is not a plain file name")
+            }
+            Error::UnsupportedNativeTarget(target) => {
+                format!("Native executables can't be spawned for {target}")
+            }
+            Error::Source(source) => source.error.message(),
+        }
+    }
+}
+
+/// 1-based line and column of the byte at `offset`
+pub(crate) fn span_at(text: &str, offset: usize) -> Option<Span> {
+    let before = text.get(..offset)?;
+    let line_start = before.rfind('\n').map_or(0, |idx| idx + 1);
+    Some(Span {
+        line: before.matches('\n').count() + 1,
+        column: before[line_start..].chars().count() + 1,
+    })
+}
+
+/// Byte offset of the 1-based `span` in `text`, the inverse of [`span_at`].
+///
+/// Columns past the end of the line point at its end.
+pub(crate) fn offset_at(text: &str, span: Span) -> Option<usize> {
+    let line_start = match span.line.checked_sub(2) {
+        None => 0,
+        Some(newlines) => text.match_indices('\n').nth(newlines)?.0 + 1,
+    };
+    let line = text[line_start..].split('\n').next().unwrap_or_default();
+    let column = line
+        .char_indices()
+        .nth(span.column.saturating_sub(1))
+        .map_or(line.len(), |(idx, _)| idx);
+    Some(line_start + column)
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Source(source) => write!(f, "{source}"),
+            _ => match self.span("") {
+                Some(span) => write!(f, "{} at {span}", self.message()),
+                None => write!(f, "{}", self.message()),
+            },
+        }
+    }
+}
+
+impl fmt::Display for SourceError {
+    /// Renders like
+    ///
+    /// ```text
+    /// Invalid .env line
+    ///  --> .env:2:5
+    ///   |
+    /// 2 | FOO BAR
+    ///   |     ^
+    /// ```
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", self.error.message())?;
+        let Some(span) = self.span else {
+            return write!(f, " --> {}", self.name);
+        };
+        write!(f, " --> {}:{}:{}", self.name, span.line, span.column)?;
+        let Some(snippet) = &self.snippet else {
+            return Ok(());
+        };
+        let number = span.line.to_string();
+        let gutter = " ".repeat(number.len());
+        // Keep tabs so the caret lines up with the snippet
+        let padding = snippet
+            .chars()
+            .take(span.column.saturating_sub(1))
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect::<String>();
+        write!(
+            f,
+            "\n{gutter} |\n{number} | {snippet}\n{gutter} | {padding}^"
+        )
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            Error::Json(err) => Some(err),
+            Error::Yaml(err) => Some(err),
+            Error::Toml(err) => Some(err),
+            Error::TomlSerialize(err) => Some(err),
+            Error::Xml(err) => Some(err),
+            Error::Csv(err) => Some(err),
+            Error::Source(source) => Some(&source.error),
+            _ => None,
+        }
+    }
 }
 
 impl From<io::Error> for Error {
@@ -22,4 +283,34 @@ impl From<serde_json::Error> for Error {
     }
 }
 
+impl From<serde_yaml::Error> for Error {
+    fn from(e: serde_yaml::Error) -> Self {
+        Error::Yaml(e)
+    }
+}
+
+impl From<toml_edit::TomlError> for Error {
+    fn from(e: toml_edit::TomlError) -> Self {
+        Error::Toml(e)
+    }
+}
+
+impl From<roxmltree::Error> for Error {
+    fn from(e: roxmltree::Error) -> Self {
+        Error::Xml(e)
+    }
+}
+
+impl From<csv::Error> for Error {
+    fn from(e: csv::Error) -> Self {
+        Error::Csv(e)
+    }
+}
+
+impl From<toml::ser::Error> for Error {
+    fn from(e: toml::ser::Error) -> Self {
+        Error::TomlSerialize(e)
+    }
+}
+
 pub type Result<T> = std::result::Result<T, Error>;
@@ -1,55 +1,191 @@
-use std::io::{Cursor, Read};
-use std::{fs, io, path};
-
-use crate::dotenv;
-use crate::{Args, Error, Result};
-
-// Simply try to deserialize to our supported formats and see if it works
-fn guess_input_format(input: &str) -> Result<&'static str> {
-    let json = serde_json::from_str::<serde_json::Value>(input);
-    let yaml = serde_yaml::from_str::<serde_yaml::Value>(input);
-    let dotenv = dotenv::from_str(input);
-    match (json, yaml, dotenv) {
-        (Ok(_), _, _) => Ok("json"),
-        (_, Ok(_), _) => Ok("yaml"),
-        (_, _, Ok(_)) => Ok("dotenv"),
-        _ => Err(Error::InvalidInputFormat(
-            "Unsupported data format from stdin".to_owned(),
-        )),
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+use std::sync::LazyLock;
+
+use clap::ValueEnum;
+use regex::Regex;
+
+use crate::{Error, Result};
+
+/// Config formats picnic can read
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    Json,
+    Xml,
+    Toml,
+    Yaml,
+    Csv,
+    Dotenv,
+}
+
+impl Format {
+    /// Pick the format from a file extension
+    #[must_use]
+    pub fn from_extension(ext: &str) -> Option<Self> {
+        match ext {
+            "json" => Some(Format::Json),
+            "yaml" | "yml" => Some(Format::Yaml),
+            "toml" => Some(Format::Toml),
+            "xml" => Some(Format::Xml),
+            "csv" | "tsv" => Some(Format::Csv),
+            "env" => Some(Format::Dotenv),
+            _ => None,
+        }
     }
 }
 
+impl std::fmt::Display for Format {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Format::Json => write!(f, "json"),
+            Format::Yaml => write!(f, "yaml"),
+            Format::Toml => write!(f, "toml"),
+            Format::Xml => write!(f, "xml"),
+            Format::Csv => write!(f, "csv"),
+            Format::Dotenv => write!(f, "dotenv"),
+        }
+    }
+}
+
+static TOML_TABLE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"^\[\[?[\w.\-" ]+\]\]?$"#).unwrap());
+// Bare words are not toml values, which tells `port = 80` apart from `HOST=localhost`
+static TOML_PAIR: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r#"^[\w.\-"']+\s*=\s*(["'\[{]|true$|false$|[+-]?(\d|inf$|nan$))"#).unwrap()
+});
+static YAML_LINE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^(---|-(\s|$)|[^\s:#\-][^:]*:(\s|$))").unwrap());
+static DOTENV_PAIR: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^(export\s+)?[A-Za-z_][\w.]*\s*=").unwrap());
+
+/// Delimiters a csv row could be split by
+const CSV_DELIMITERS: [char; 4] = [',', '\t', ';', '|'];
+
+/// Formats `input` looks like along with how much it does, most likely first.
+///
+/// Every format gets a score, the number of lines that look like it. Documents
+/// wrapped in `{}`, `[]` or `<>` are claimed by json and xml as a whole. Ties go
+/// to the more specific format, in the order they are listed in [`Format`].
+///
+/// This only looks at the shape of each line once, candidates still need to be
+/// parsed to know if they are right.
+#[must_use]
+pub fn candidates(input: &str) -> Vec<(Format, usize)> {
+    let lines = input
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>();
+    // Nothing to parse is a valid empty .env
+    if lines.is_empty() {
+        return vec![(Format::Dotenv, 0)];
+    }
+    // `#` comments don't tell yaml, toml and .env apart
+    let uncommented = lines
+        .iter()
+        .filter(|line| !line.starts_with('#'))
+        .copied()
+        .collect::<Vec<_>>();
+    let count = |pattern: &Regex| uncommented.iter().filter(|l| pattern.is_match(l)).count();
+
+    let trimmed = input.trim();
+    let wrapped = |open, close| trimmed.starts_with(open) && trimmed.ends_with(close);
+    // One more than any line based format could score
+    let whole = lines.len() + 1;
+
+    let mut scores = vec![
+        (
+            Format::Json,
+            if wrapped('{', '}') || wrapped('[', ']') {
+                whole
+            } else {
+                0
+            },
+        ),
+        (Format::Xml, if wrapped('<', '>') { whole } else { 0 }),
+        (Format::Toml, count(&TOML_TABLE) + count(&TOML_PAIR)),
+        (Format::Yaml, count(&YAML_LINE)),
+        (Format::Csv, csv_score(&lines)),
+        (Format::Dotenv, count(&DOTENV_PAIR)),
+    ];
+    scores.retain(|(_, score)| *score > 0);
+    // Stable, so ties keep their specificity order
+    scores.sort_by(|(_, a), (_, b)| b.cmp(a));
+    scores
+}
+
+/// Every line when they all split into the same number of fields (at least two)
+fn csv_score(lines: &[&str]) -> usize {
+    // A header of field names doesn't look like a key/value pair
+    if lines.len() < 2 || lines[0].contains('=') || YAML_LINE.is_match(lines[0]) {
+        return 0;
+    }
+    let fields = |line: &str, delimiter| line.matches(delimiter).count();
+    let is_table = |delimiter| {
+        let header = fields(lines[0], delimiter);
+        header > 0 && lines.iter().all(|line| fields(line, delimiter) == header)
+    };
+    if CSV_DELIMITERS.into_iter().any(is_table) {
+        lines.len()
+    } else {
+        0
+    }
+}
+
+/// Raw input along with the format it should be parsed as, if known
 pub struct Input {
-    pub ext: String,
+    /// The file path, or `<stdin>`, for error messages
+    pub name: String,
+    /// `None` when it has to be detected from the content
+    pub format: Option<Format>,
+    /// Csv delimiter the file name implies, a tab for `.tsv` files
+    pub delimiter: Option<u8>,
     pub reader: Box<dyn Read>,
 }
 
 impl Input {
-    pub fn from_args(args: &Args) -> Result<Self> {
-        if let Some(ref file) = args.file {
-            let meta = fs::metadata(file)?;
-            // Sanity check
-            if !meta.is_file() {
-                return Err(Error::NotAFile);
-            }
-
-            let ext = path::Path::new(file)
-                .extension()
-                .map(|s| s.to_string_lossy().to_string())
-                .unwrap_or_default();
-            Ok(Self {
-                ext,
-                reader: Box::new(fs::File::open(file)?),
-            })
-        } else {
-            // If the user didn't provide a file, we'll try to read from stdin
-            let mut input = String::new();
-            io::stdin().read_to_string(&mut input)?;
-            let ext = guess_input_format(&input)?.to_owned();
-            Ok(Self {
-                ext,
-                reader: Box::new(Cursor::new(input)),
-            })
+    /// Open a file as `format`, or as whatever its extension says. Files with
+    /// unknown extensions have their format detected when parsed.
+    ///
+    /// # Errors
+    ///
+    /// Fails when `path` is not a readable file.
+    pub fn from_path(path: impl AsRef<Path>, format: Option<Format>) -> Result<Self> {
+        let path = path.as_ref();
+        let name = path.display().to_string();
+        let meta = fs::metadata(path).map_err(|err| Error::from(err).in_source(&name, None))?;
+        // Sanity check
+        if !meta.is_file() {
+            return Err(Error::NotAFile.in_source(&name, None));
+        }
+        let file = fs::File::open(path).map_err(|err| Error::from(err).in_source(&name, None))?;
+
+        let ext = path
+            .extension()
+            .map(|s| s.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let is_dotenv = path
+            .file_name()
+            .is_some_and(|name| name == ".env" || name.to_string_lossy().starts_with(".env."));
+        Ok(Self {
+            name,
+            format: format
+                .or_else(|| Format::from_extension(&ext))
+                .or(is_dotenv.then_some(Format::Dotenv)),
+            delimiter: (ext == "tsv").then_some(b'\t'),
+            reader: Box::new(file),
+        })
+    }
+
+    /// Read stdin as `format`, or detect its format when parsed.
+    #[must_use]
+    pub fn from_stdin(format: Option<Format>) -> Self {
+        Self {
+            name: "<stdin>".to_owned(),
+            format,
+            delimiter: None,
+            reader: Box::new(io::stdin()),
         }
     }
 }
@@ -1,61 +1,70 @@
-use regex::Regex;
-use serde_json::Value;
-use std::collections::BTreeMap;
+use serde_json::{Map, Value};
+use std::io::{BufReader, Read};
 
-use crate::output::SEPARATOR_THAT_WONT_COLIDE_FOR_SURE;
+use crate::tree::{Kind, Node, Scalar};
 use crate::Result;
 
-pub type Map = BTreeMap<String, Value>;
+/// Parse a json document
+///
+/// # Errors
+///
+/// Fails when the input is not valid json.
+pub fn from_str(input: &str) -> Result<Node> {
+    Ok(into_node(serde_json::from_str(input)?))
+}
+
+/// See [`from_str`]
+///
+/// # Errors
+///
+/// Fails when the input is not valid json, or can't be read.
+pub fn from_reader(read: impl Read) -> Result<Node> {
+    Ok(into_node(serde_json::from_reader(BufReader::new(read))?))
+}
+
+/// Serialize a tree as pretty printed json
+///
+/// # Errors
+///
+/// Fails when the tree can't be serialized.
+pub fn to_string(node: &Node) -> Result<String> {
+    Ok(serde_json::to_string_pretty(&from_node(node))?)
+}
+
+/// Single line json, for when a tree has to fit in a key
+pub(crate) fn to_compact_string(node: &Node) -> String {
+    from_node(node).to_string()
+}
 
-// Fixes json data like: { "foo": $bar, "baz": { "borg": $boo } }
-pub fn into_fixed(value: &str) -> Result<Value> {
-    let re = Regex::new(r"\$\s*\b(\w+)\b").unwrap();
-    let corrected_str =
-        re.replace_all(value, |caps: &regex::Captures| format!(r#""{}""#, &caps[1]));
-    Ok(serde_json::from_str(&corrected_str)?)
+fn into_node(value: Value) -> Node {
+    match value {
+        Value::Null => Scalar::Null.into(),
+        Value::Bool(bool) => Scalar::Bool(bool).into(),
+        // Numbers keep their original textual representation
+        Value::Number(number) => Scalar::Number(number.to_string()).into(),
+        Value::String(string) => Scalar::String(string).into(),
+        Value::Array(array) => Node::seq(array.into_iter().map(into_node).collect()),
+        Value::Object(map) => Node::map(
+            map.into_iter()
+                .map(|(key, value)| (key, into_node(value)))
+                .collect(),
+        ),
+    }
 }
 
-pub fn dfs_flatten(data: &Value, flattened: &mut BTreeMap<String, Value>, parent: &str) {
-    match data {
-        _map if data.is_object() => {
-            if let Some(map) = data.as_object() {
-                for (key, value) in map {
-                    if value.is_object() || value.is_array() {
-                        dfs_flatten(value, flattened, key.as_str());
-                    } else {
-                        if parent.is_empty() {
-                            flattened.insert(key.clone(), value.clone());
-                            continue;
-                        }
-                        flattened.insert(
-                            format!(
-                                "{}{}{}",
-                                parent,
-                                SEPARATOR_THAT_WONT_COLIDE_FOR_SURE,
-                                key.as_str()
-                            ),
-                            value.clone(),
-                        );
-                    }
-                }
-            }
-        }
-        _array if data.is_array() => {
-            if let Some(array) = data.as_array() {
-                for (idx, value) in array.iter().enumerate() {
-                    dfs_flatten(
-                        value,
-                        flattened,
-                        format!("{parent}{SEPARATOR_THAT_WONT_COLIDE_FOR_SURE}{idx}").as_str(),
-                    );
-                }
-            }
-        }
-        _string if data.is_string() => {
-            if let Some(string) = data.as_str() {
-                flattened.insert(parent.to_owned(), Value::String(string.to_owned()));
-            }
-        }
-        _ => (),
+fn from_node(node: &Node) -> Value {
+    match &node.kind {
+        Kind::Scalar(Scalar::Null) => Value::Null,
+        Kind::Scalar(Scalar::Bool(bool)) => Value::Bool(*bool),
+        Kind::Scalar(Scalar::Number(number)) => number
+            .parse()
+            .map_or_else(|_| Value::String(number.clone()), Value::Number),
+        Kind::Scalar(Scalar::String(string)) => Value::String(string.clone()),
+        Kind::Seq(seq) => Value::Array(seq.iter().map(from_node).collect()),
+        Kind::Map(map) => Value::Object(
+            map.iter()
+                .map(|(key, node)| (key.clone(), from_node(node)))
+                .collect::<Map<_, _>>(),
+        ),
     }
 }
@@ -0,0 +1,59 @@
+//! PICNIC Is Config Notation Interpreter/Converter
+//!
+//! Reads `json`, `yaml`, `toml`, `xml`, `csv` and `.env` files, flattens them
+//! into `key = value` entries and prints those as shell variables or converts
+//! them to another format.
+//!
+//! ```no_run
+//! use picnic::{FlattenOptions, Output, OutputOptions};
+//!
+//! # fn main() -> picnic::Result<()> {
+//! let entries = picnic::load("some.json")?
+//!     .flatten(FlattenOptions::default())
+//!     .matching(r#"{"foo": $FOO}"#)?;
+//!
+//! let mut output = Output::new(OutputOptions::default(), std::io::stdout());
+//! for entry in &entries {
+//!     output.print(entry)?;
+//! }
+//! output.finish()?;
+//! # Ok(())
+//! # }
+//! ```
+#![warn(clippy::all, clippy::pedantic, clippy::cargo)]
+
+use std::path::Path;
+
+pub mod convert;
+pub mod csv;
+pub mod document;
+pub mod dotenv;
+pub mod error;
+pub mod input;
+pub mod json;
+mod native;
+pub mod output;
+pub mod query;
+pub mod template;
+pub mod toml;
+pub mod tree;
+pub mod xml;
+pub mod yaml;
+
+pub use document::{
+    Arrays, Casing, Document, Entry, FlattenOptions, Flattened, MergeOptions, ParseOptions,
+    Subtrees,
+};
+pub use error::{Error, Result};
+pub use input::{Format, Input};
+pub use output::{Output, OutputOptions};
+pub use tree::{Node, Scalar, Span};
+
+/// Read and parse a config file, picking the format from its extension or content.
+///
+/// # Errors
+///
+/// Fails when the file can't be read or is not valid in its format.
+pub fn load(path: impl AsRef<Path>) -> Result<Document> {
+    Document::from_input(Input::from_path(path, None)?, ParseOptions::default())
+}
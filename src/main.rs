@@ -1,33 +1,44 @@
 #![warn(clippy::all, clippy::pedantic, clippy::cargo)]
 
+use std::collections::BTreeMap;
 use std::ffi::OsString;
 use std::io;
+use std::process::ExitCode;
+use std::time::Duration;
 
 use clap::Parser;
 
-mod dotenv;
-mod error;
-mod input;
-mod json;
-mod output;
-
-use error::{Error, Result};
-use input::Input;
-use output::{Casing, Output};
+use picnic::dotenv::{EnvPrecedence, EnvVars, Expansion, UnsetVars};
+use picnic::output::{self, Collisions, InvalidKeys, Nulls, Shell};
+use picnic::{convert, input};
+use picnic::{
+    Arrays, Casing, Document, Error, FlattenOptions, Flattened, Input, MergeOptions, Output,
+    OutputOptions, ParseOptions, Result, Subtrees,
+};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
-pub struct Args {
-    /// Input file
+struct Args {
+    /// Input files, deep merged in order with later ones winning.
+    /// Reads stdin when none are given.
     #[arg(name = "FILE")]
-    file: Option<OsString>,
-    /// Spawn tiny binaries at `PATH` with named with the given `key` paths that when executed return `value`.
+    files: Vec<OsString>,
+    /// Format of the inputs.
+    /// Guessed from the file extension, or from the content when there is none.
+    #[arg(short, long, name = "INPUT_FORMAT")]
+    format: Option<input::Format>,
+    /// Spawn tiny binaries at `PATH` named after the given `key` paths that when executed return `value`.
     ///
     /// Use '.' to spawn at the current directory
     ///
     /// Use 'temp' to spawn at your system temporary directory
     #[arg(short = 'o', long, name = "PATH")]
     spawn: Option<OsString>,
+    /// Spawn tiny static native executables instead of shell scripts,
+    /// for environments where no shell is guaranteed (e.g. Nix sandboxes).
+    /// (Only `x86_64` and `aarch64` linux)
+    #[arg(long, requires = "PATH")]
+    native: bool,
     /// Match keys with the given template
     ///
     /// json:
@@ -38,10 +49,42 @@ pub struct Args {
     ///     ...
     ///  }'
     ///
+    /// yaml:
+    ///  --match '{ a: $FOO, b: [_, _, $BAR], ... }'
+    ///
+    /// toml:
+    ///  --match 'a = $FOO
+    ///  b = [$BAR]
+    ///  [c]
+    ///  d = $BORG'
+    ///
+    /// xml:
+    ///  --match '<a foo="$FOO"><b>$BAR</b></a>'
+    ///
+    /// csv:
+    ///  --match 'name,port
+    ///  $NAME,$PORT'
+    ///
     /// .env:
     /// --match 'a=$BAZ;b=$BURG;...'
+    ///
+    /// `_` skips a position. Missing values are left out, unless given a default
+    /// with `$PORT:-8080` or required with `$API_KEY!`.
     #[arg(short, long, name = "TEMPLATE")]
     r#match: Option<String>,
+    /// Select values with a JSON path query instead of a template, keyed by their path.
+    ///
+    /// e.g. --query '$.services[?(@.enabled)].name'
+    ///
+    /// Supports `.key`, `['key']`, `[0]`, `[-1]`, `*`, `..key`, unions like `[0, 2]`
+    /// and filters comparing `@` paths with `==`, `!=`, `<`, `<=`, `>`, `>=`, `!`, `&&` and `||`.
+    #[arg(short, long, name = "QUERY", conflicts_with = "TEMPLATE")]
+    query: Option<String>,
+    /// How template variables bound to a whole map or sequence are printed,
+    /// e.g. `{"db": $DB}` gives `DB='{"host":"localhost"}'` as json
+    /// or `DB_host='localhost'` when flattened.
+    #[arg(long, name = "SUBTREES", default_value_t = Subtrees::Json)]
+    subtrees: Subtrees,
     /// Separator used to chain nesting keys.
     /// (Not applicable for .env files)
     #[arg(short, long, default_value_t = '.')]
@@ -49,92 +92,189 @@ pub struct Args {
     /// Case sensitivity for the output keys.
     #[arg(short, long, name = "CASING", default_value_t = Casing::Insensitive)]
     casing: Casing,
+    /// How to render `null` values.
+    /// (Not applicable for .env files)
+    #[arg(long = "null", name = "NULLS", default_value_t = Nulls::Empty)]
+    nulls: Nulls,
+    /// What to do when distinct paths end up as the same key
+    /// once the separator and casing are applied.
+    #[arg(long, name = "ACTION", default_value_t = Collisions::Error)]
+    collisions: Collisions,
+    /// What to do with keys that are not valid shell variable names,
+    /// e.g. `baz.quz` becomes `baz_quz` when sanitized.
+    #[arg(long, name = "KEYS", default_value_t = InvalidKeys::Sanitize)]
+    invalid_keys: InvalidKeys,
+    /// Shell dialect of the printed variables.
+    /// Detected from `$SHELL` when not given.
+    #[arg(long, name = "SHELL")]
+    shell: Option<Shell>,
+    /// Convert to another config format instead of printing shell variables.
+    /// Keys are nested back on the separator for tree shaped formats.
+    #[arg(long, name = "FORMAT")]
+    to: Option<convert::Format>,
+    /// How arrays of several input files are merged.
+    #[arg(long, name = "STRATEGY", default_value_t = Arrays::Replace)]
+    arrays: Arrays,
+    /// Show which input the value of `KEY` came from instead of printing variables.
+    /// Takes the key as printed, e.g. `db_host`, or as flattened, e.g. `db.host`.
+    #[arg(long, name = "KEY")]
+    explain: Option<String>,
+    /// Prefix for xml attribute keys, e.g. `server.@port`.
+    /// (Only applicable for .xml files)
+    #[arg(long, name = "PREFIX", default_value = "@")]
+    attribute_prefix: String,
+    /// Key csv rows by the values of this column instead of by their index.
+    /// (Only applicable for .csv and .tsv files)
+    #[arg(long, name = "COLUMN")]
+    key_column: Option<String>,
+    /// What `$NAME` and `${NAME}` expand to when `NAME` is not set.
+    /// (Only applicable for .env files)
+    #[arg(long, name = "UNSET", default_value_t = UnsetVars::Empty)]
+    unset_vars: UnsetVars,
+    /// Don't look variables up in the process environment in .env expansions.
+    #[arg(long, conflicts_with = "ENV_FILE")]
+    no_env: bool,
+    /// Whether the process environment or a file's own earlier lines win in .env
+    /// expansions when both set a variable.
+    #[arg(long, name = "PRECEDENCE", default_value_t = EnvPrecedence::Process)]
+    env_precedence: EnvPrecedence,
+    /// Look .env expansion variables up in this .env file instead of the process
+    /// environment. Can be repeated, later files win.
+    #[arg(long, name = "ENV_FILE")]
+    env_file: Vec<OsString>,
+    /// Run `$(command)` and `` `command` `` substitutions in .env files.
+    /// Without it they are kept as written, with a warning.
+    #[arg(long)]
+    allow_exec: bool,
+    /// Seconds a .env command substitution may run before failing.
+    #[arg(
+        long,
+        name = "SECONDS",
+        default_value = "10",
+        value_parser = parse_seconds,
+        requires = "allow_exec"
+    )]
+    exec_timeout: Duration,
 }
 
-fn main() -> Result<()> {
-    let args = Args::parse();
-    let input = Input::from_args(&args)?;
-    let output = Output::from_args(&args);
-
-    match input.ext {
-        ext if ext == "json" => {
-            let reader = io::BufReader::new(input.reader);
-            let data: serde_json::Value = serde_json::from_reader(reader)?;
+fn parse_seconds(seconds: &str) -> std::result::Result<Duration, String> {
+    let seconds = seconds.parse::<f64>().map_err(|err| err.to_string())?;
+    Duration::try_from_secs_f64(seconds).map_err(|err| err.to_string())
+}
 
-            // Flattened json data
-            let mut flattened = json::Map::new();
-            json::dfs_flatten(&data, &mut flattened, "");
+fn main() -> ExitCode {
+    match run(Args::parse()) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("Error: {err}");
+            ExitCode::from(err.exit_code())
+        }
+    }
+}
 
-            // Flattened matches
-            let maybe_matches = if let Some(ref matches) = args.r#match {
-                let matches = json::into_fixed(matches)?;
-                let mut flattened_to_match = json::Map::new();
-                json::dfs_flatten(&matches, &mut flattened_to_match, "");
-                Some(flattened_to_match)
+fn run(args: Args) -> Result<()> {
+    let options = ParseOptions {
+        attribute_prefix: args.attribute_prefix,
+        key_column: args.key_column,
+        delimiter: None,
+        expansion: Expansion {
+            unset_vars: args.unset_vars,
+            env: if !args.env_file.is_empty() {
+                EnvVars::from_files(&args.env_file)?
+            } else if args.no_env {
+                EnvVars::Only(BTreeMap::new())
             } else {
-                None
-            };
+                EnvVars::Process
+            },
+            precedence: args.env_precedence,
+            allow_exec: args.allow_exec.then_some(args.exec_timeout),
+        },
+    };
+    let (first, rest) = match args.files.split_first() {
+        Some((first, rest)) => (Input::from_path(first, args.format)?, rest),
+        // If the user didn't provide a file, we'll try to read from stdin
+        None => (Input::from_stdin(args.format), &[][..]),
+    };
+    let mut document = Document::from_input(first, options.clone())?;
+    for file in rest {
+        let other = Document::from_input(Input::from_path(file, args.format)?, options.clone())?;
+        document = document.merge(
+            other,
+            MergeOptions {
+                arrays: args.arrays,
+                separator: args.separator,
+            },
+        )?;
+    }
 
-            for (key, value) in flattened {
-                // We are matching the keys with the given template
-                if let Some(matches) = &maybe_matches {
-                    // If the key is not in the matches, skip it
-                    if matches.contains_key(&key) {
-                        let var_name = matches
-                            .get(&key)
-                            .ok_or(Error::InvalidMatchTemplate(key))?
-                            .to_string()
-                            // Remove surrounding quotes
-                            .replace('\"', "");
-                        output.print(&var_name, value.as_str().unwrap_or_default())?;
-                    }
-                } else {
-                    output.print(&key, value.as_str().unwrap_or_default())?;
-                }
-            }
-        }
-        ext if ext == "yaml" || ext == "yml" => {
-            unimplemented!("YAML support is not implemented yet")
-        }
-        ext if ext == "toml" => {
-            unimplemented!("TOML support is not implemented yet")
-        }
-        ext if ext == "xml" => {
-            unimplemented!("XML support is not implemented yet")
-        }
-        // Assuming it's a .env like file
-        _ => {
-            let lines = dotenv::from_reader(input.reader);
-            let maybe_matches = if let Some(ref matches) = args.r#match {
-                let matches = dotenv::into_fixed(matches);
-                let mut map = dotenv::Map::new();
-                let matched_vars = dotenv::from_str(&matches)?;
-                for kv in matched_vars {
-                    let (key, value) = kv?;
-                    map.insert(key, value);
-                }
-                Some(map)
-            } else {
-                None
-            };
+    for warning in document.warnings() {
+        eprintln!("Warning: {warning}");
+    }
 
-            for line in lines {
-                let (key, value) = line?;
-                if let Some(matches) = &maybe_matches {
-                    if matches.contains_key(&key) {
-                        let var_name = matches
-                            .get(&key)
-                            .ok_or(Error::InvalidMatchTemplate(key))?
-                            .to_string()
-                            // Remove surrounding quotes
-                            .replace('\"', "");
-                        output.print(&var_name, &value.to_string())?;
-                    }
-                } else {
-                    output.print(&key, &value.to_string())?;
-                }
-            }
-        }
+    let mut entries = document.flatten(FlattenOptions {
+        separator: args.separator,
+        casing: args.casing,
+        subtrees: args.subtrees,
+    });
+    if let Some(ref template) = args.r#match {
+        entries = entries.matching(template)?;
+    }
+    if let Some(ref query) = args.query {
+        entries = entries.query(query)?;
+    }
+    if let Some(ref key) = args.explain {
+        return explain(&entries, key);
+    }
+
+    let mut output = Output::new(
+        OutputOptions {
+            separator: args.separator,
+            nulls: args.nulls,
+            collisions: args.collisions,
+            invalid_keys: args.invalid_keys,
+            shell: args.shell.unwrap_or_else(Shell::detect),
+            to: args.to,
+            spawn: args.spawn.as_deref().map(output::spawn_dir),
+            native: args.native,
+        },
+        io::stdout(),
+    );
+    for entry in &entries {
+        output.print(entry)?;
+    }
+    for warning in output.warnings() {
+        eprintln!("Warning: {warning}");
+    }
+    output.finish()?;
+    for path in output.spawned() {
+        eprintln!("Spawned {}", path.display());
     }
     Ok(())
 }
+
+// Print where the entries going by `key` came from
+fn explain(entries: &Flattened, key: &str) -> Result<()> {
+    let mut found = false;
+    for entry in entries {
+        let printed = InvalidKeys::Sanitize.apply(&entry.key)?;
+        if entry.key != key && printed != key {
+            continue;
+        }
+        found = true;
+        let value = entry.value.as_str().unwrap_or("null");
+        let sources = entries.sources(entry);
+        match (sources.as_slice(), entry.span) {
+            ([], _) => println!("{printed}='{value}' from the --match default"),
+            ([source], Some(span)) => println!(
+                "{printed}='{value}' from {source}:{}:{}",
+                span.line, span.column
+            ),
+            (sources, _) => println!("{printed}='{value}' from {}", sources.join(", ")),
+        }
+    }
+    if found {
+        Ok(())
+    } else {
+        Err(Error::UnknownKey(key.to_owned()))
+    }
+}
@@ -0,0 +1,127 @@
+//! Tiny static executables that print a fixed value, for places where no shell
+//! is guaranteed (e.g. Nix sandboxes).
+//!
+//! The executable is a bare ELF with a single loadable segment holding a
+//! `write(1, value, len)` loop, an `exit` and the value right after the code.
+use crate::{Error, Result};
+
+const BASE_ADDRESS: u64 = 0x40_0000;
+const ELF_HEADER_SIZE: u16 = 64;
+const PROGRAM_HEADER_SIZE: u16 = 56;
+/// Both headers are 8 byte aligned, so is the code
+const CODE_OFFSET: usize = (ELF_HEADER_SIZE + PROGRAM_HEADER_SIZE) as usize;
+
+/// `x86_64` machine code, see `X86_64_LEN_AT` for where the value length goes
+///
+/// ```asm
+///     lea rsi, [rip + data]
+///     movabs rdx, len
+///     test rdx, rdx
+///     jz done
+/// loop:
+///     mov eax, 1          ; write
+///     mov edi, 1          ; stdout
+///     syscall
+///     test rax, rax
+///     jle fail
+///     add rsi, rax
+///     sub rdx, rax
+///     jnz loop
+/// done:
+///     xor edi, edi
+///     jmp exit
+/// fail:
+///     mov edi, 1
+/// exit:
+///     mov eax, 60         ; exit
+///     syscall
+/// data:
+/// ```
+const X86_64_CODE: [u8; 63] = [
+    0x48, 0x8d, 0x35, 0x38, 0x00, 0x00, 0x00, 0x48, 0xba, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x48, 0x85, 0xd2, 0x74, 0x19, 0xb8, 0x01, 0x00, 0x00, 0x00, 0xbf, 0x01, 0x00, 0x00, 0x00,
+    0x0f, 0x05, 0x48, 0x85, 0xc0, 0x7e, 0x0c, 0x48, 0x01, 0xc6, 0x48, 0x29, 0xc2, 0x75, 0xe7, 0x31,
+    0xff, 0xeb, 0x05, 0xbf, 0x01, 0x00, 0x00, 0x00, 0xb8, 0x3c, 0x00, 0x00, 0x00, 0x0f, 0x05,
+];
+const X86_64_LEN_AT: usize = 9;
+
+/// `aarch64` machine code, the value length is the trailing 8 byte literal
+///
+/// ```asm
+///     adr x1, data
+///     ldr x2, len
+///     cbz x2, done
+/// loop:
+///     mov x0, #1          // stdout
+///     mov x8, #64         // write
+///     svc #0
+///     cmp x0, #0
+///     b.le fail
+///     add x1, x1, x0
+///     subs x2, x2, x0
+///     b.ne loop
+/// done:
+///     mov x0, #0
+///     b exit
+/// fail:
+///     mov x0, #1
+/// exit:
+///     mov x8, #93         // exit
+///     svc #0
+/// len:
+///     .quad 0
+/// data:
+/// ```
+const AARCH64_CODE: [u8; 72] = [
+    0x41, 0x02, 0x00, 0x10, 0xe2, 0x01, 0x00, 0x58, 0x22, 0x01, 0x00, 0xb4, 0x20, 0x00, 0x80, 0xd2,
+    0x08, 0x08, 0x80, 0xd2, 0x01, 0x00, 0x00, 0xd4, 0x1f, 0x00, 0x00, 0xf1, 0xcd, 0x00, 0x00, 0x54,
+    0x21, 0x00, 0x00, 0x8b, 0x42, 0x00, 0x00, 0xeb, 0x21, 0xff, 0xff, 0x54, 0x00, 0x00, 0x80, 0xd2,
+    0x02, 0x00, 0x00, 0x14, 0x20, 0x00, 0x80, 0xd2, 0xa8, 0x0b, 0x80, 0xd2, 0x01, 0x00, 0x00, 0xd4,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+const AARCH64_LEN_AT: usize = 64;
+
+/// Build an executable for the running platform that prints exactly `value`
+pub fn executable(value: &[u8]) -> Result<Vec<u8>> {
+    let (machine, align, code, len_at): (u16, u64, &[u8], usize) =
+        match (std::env::consts::OS, std::env::consts::ARCH) {
+            ("linux", "x86_64") => (0x3e, 0x1000, &X86_64_CODE, X86_64_LEN_AT),
+            // Pages can be up to 64K there
+            ("linux", "aarch64") => (0xb7, 0x1_0000, &AARCH64_CODE, AARCH64_LEN_AT),
+            (os, arch) => return Err(Error::UnsupportedNativeTarget(format!("{arch}-{os}"))),
+        };
+
+    let mut code = code.to_vec();
+    code[len_at..len_at + 8].copy_from_slice(&(value.len() as u64).to_le_bytes());
+    let file_size = (CODE_OFFSET + code.len() + value.len()) as u64;
+
+    let mut elf = Vec::with_capacity(CODE_OFFSET + code.len() + value.len());
+    // ELF header: magic, 64 bit, little endian, version 1, System V ABI
+    elf.extend_from_slice(&[0x7f, b'E', b'L', b'F', 2, 1, 1, 0]);
+    elf.extend_from_slice(&[0; 8]);
+    elf.extend_from_slice(&2_u16.to_le_bytes()); // executable
+    elf.extend_from_slice(&machine.to_le_bytes());
+    elf.extend_from_slice(&1_u32.to_le_bytes()); // version
+    elf.extend_from_slice(&(BASE_ADDRESS + CODE_OFFSET as u64).to_le_bytes()); // entry
+    elf.extend_from_slice(&u64::from(ELF_HEADER_SIZE).to_le_bytes()); // program headers
+    elf.extend_from_slice(&0_u64.to_le_bytes()); // no section headers
+    elf.extend_from_slice(&0_u32.to_le_bytes()); // flags
+    elf.extend_from_slice(&ELF_HEADER_SIZE.to_le_bytes());
+    elf.extend_from_slice(&PROGRAM_HEADER_SIZE.to_le_bytes());
+    elf.extend_from_slice(&1_u16.to_le_bytes()); // one program header
+    elf.extend_from_slice(&[0; 6]); // section header size, count and names index
+
+    // Program header: the whole file as a single read + execute segment
+    elf.extend_from_slice(&1_u32.to_le_bytes()); // loadable
+    elf.extend_from_slice(&5_u32.to_le_bytes()); // r-x
+    elf.extend_from_slice(&0_u64.to_le_bytes()); // file offset
+    elf.extend_from_slice(&BASE_ADDRESS.to_le_bytes()); // virtual address
+    elf.extend_from_slice(&BASE_ADDRESS.to_le_bytes()); // physical address
+    elf.extend_from_slice(&file_size.to_le_bytes());
+    elf.extend_from_slice(&file_size.to_le_bytes());
+    elf.extend_from_slice(&align.to_le_bytes());
+
+    elf.extend_from_slice(&code);
+    elf.extend_from_slice(value);
+    Ok(elf)
+}
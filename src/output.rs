@@ -1,106 +1,440 @@
-use crate::{Args, Result};
+use std::collections::BTreeMap;
 use std::ffi::OsStr;
+use std::fmt::Write as _;
 use std::fs;
-use std::os::unix::fs::PermissionsExt;
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
 
 use clap::ValueEnum;
 
-pub const SEPARATOR_THAT_WONT_COLIDE_FOR_SURE: char = '\u{1F}';
+use crate::convert::Format;
+use crate::document::Entry;
+use crate::tree::Scalar;
+use crate::{native, Error, Result};
 
-pub struct Output<'args> {
-    casing: Casing,
-    separator: char,
-    spawn: Option<&'args OsStr>,
+/// How entries get printed
+#[derive(Debug, Clone)]
+pub struct OutputOptions {
+    /// Separator keys are nested back on when converting to tree shaped formats
+    pub separator: char,
+    pub nulls: Nulls,
+    pub collisions: Collisions,
+    pub invalid_keys: InvalidKeys,
+    pub shell: Shell,
+    /// Convert to another config format instead of printing shell variables
+    pub to: Option<Format>,
+    /// Directory to spawn one executable per key at
+    pub spawn: Option<PathBuf>,
+    /// Spawn native executables instead of shell scripts
+    pub native: bool,
 }
 
-impl<'args> Output<'args> {
-    fn new(casing: Casing, separator: char, spawn: Option<&'args OsStr>) -> Self {
+impl Default for OutputOptions {
+    fn default() -> Self {
         Self {
-            casing,
-            separator,
-            spawn,
+            separator: '.',
+            nulls: Nulls::default(),
+            collisions: Collisions::default(),
+            invalid_keys: InvalidKeys::default(),
+            shell: Shell::Sh,
+            to: None,
+            spawn: None,
+            native: false,
         }
     }
+}
+
+/// Prints flattened entries as shell variables, or converted to another format
+pub struct Output<W: Write = io::Stdout> {
+    options: OutputOptions,
+    writer: W,
+    // Output keys printed so far, mapped to the path that produced them
+    printed: BTreeMap<String, Vec<String>>,
+    // Original key, output key and value of every entry, held back until
+    // `finish` so a collision found late doesn't leave half the output behind
+    pending: Vec<(String, String, Scalar)>,
+    warnings: Vec<String>,
+    spawned: Vec<PathBuf>,
+}
 
-    pub fn from_args(args: &'args Args) -> Self {
-        Self::new(args.casing, args.separator, args.spawn.as_deref())
+impl<W: Write> Output<W> {
+    pub fn new(options: OutputOptions, writer: W) -> Self {
+        Self {
+            options,
+            writer,
+            printed: BTreeMap::new(),
+            pending: Vec::new(),
+            warnings: Vec::new(),
+            spawned: Vec::new(),
+        }
     }
 
-    pub fn print(&self, key: &str, value: &str) -> Result<()> {
-        // Replace key with the given separator
-        let key = key.replace(
-            SEPARATOR_THAT_WONT_COLIDE_FOR_SURE,
-            &self.separator.to_string(),
-        );
-        let key = self.casing.apply(&key);
-        // Print the thing to stdout
-        print_env(&key, value);
-        // Spawn the binary if output path was provided
-        if let Some(output) = &self.spawn {
-            spawn_binary_at(&key, value, output)?;
+    /// Collisions let through by [`Collisions::Warn`] so far
+    #[must_use]
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+
+    /// Executables spawned by [`Output::finish`]
+    #[must_use]
+    pub fn spawned(&self) -> &[PathBuf] {
+        &self.spawned
+    }
+
+    /// Queue a flattened entry, rendering `null` according to the null policy.
+    /// Nothing is written or spawned until [`Output::finish`].
+    ///
+    /// # Errors
+    ///
+    /// Fails on key collisions and invalid keys (depending on the options).
+    pub fn print(&mut self, entry: &Entry) -> Result<()> {
+        match (&entry.value, self.options.nulls) {
+            (Scalar::Null, Nulls::Empty) => {
+                self.emit(&entry.key, &entry.path, Scalar::String(String::new()))
+            }
+            (Scalar::Null, Nulls::Skip) => Ok(()),
+            (value, _) => self.emit(&entry.key, &entry.path, value.clone()),
         }
+    }
+
+    fn emit(&mut self, key: &str, path: &[String], value: Scalar) -> Result<()> {
+        // Shells and .env files only take plain identifiers as variable names
+        let var_name = match self.options.to {
+            Some(format) if format.is_tree() => key.to_owned(),
+            _ => self.options.invalid_keys.apply(key)?,
+        };
+        self.check_collision(&var_name, path)?;
+        self.pending.push((key.to_owned(), var_name, value));
+        Ok(())
+    }
+
+    /// Spawn and print every queued entry, or convert them all at once
+    ///
+    /// # Errors
+    ///
+    /// Fails when spawning fails, or when the entries can't be converted or
+    /// written.
+    pub fn finish(&mut self) -> Result<()> {
+        let pending = std::mem::take(&mut self.pending);
+        // Spawn the binaries if output path was provided
+        if let Some(output) = &self.options.spawn {
+            for (key, _, value) in &pending {
+                let rendered = value.as_str().unwrap_or("null");
+                let path = spawn_binary_at(key, rendered, output, self.options.native)?;
+                self.spawned.push(path);
+            }
+        }
+        if let Some(format) = self.options.to {
+            let pairs = pending
+                .into_iter()
+                .map(|(_, var_name, value)| (var_name, value))
+                .collect();
+            let serialized = format.serialize(pairs, self.options.separator)?;
+            write!(self.writer, "{serialized}")?;
+            if !serialized.ends_with('\n') {
+                writeln!(self.writer)?;
+            }
+        } else {
+            // Print the thing
+            for (_, var_name, value) in &pending {
+                let rendered = value.as_str().unwrap_or("null");
+                writeln!(
+                    self.writer,
+                    "{}",
+                    self.options.shell.export(var_name, rendered)
+                )?;
+            }
+        }
+        self.writer.flush()?;
         Ok(())
     }
+
+    /// The writer everything was printed to
+    #[must_use]
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    /// Two distinct paths ending up as the same key would silently shadow each other
+    fn check_collision(&mut self, key: &str, path: &[String]) -> Result<()> {
+        let Some(previous) = self.printed.get(key) else {
+            self.printed.insert(key.to_owned(), path.to_vec());
+            return Ok(());
+        };
+        if previous == path {
+            return Ok(());
+        }
+        let message = format!(
+            "Key `{key}` is produced by both {} and {}",
+            display_path(previous),
+            display_path(path)
+        );
+        match self.options.collisions {
+            Collisions::Error => Err(Error::KeyCollision(message)),
+            Collisions::Warn => {
+                self.warnings.push(message);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Render a flattened path unambiguously, e.g. `["a.b", "c"]`
+fn display_path(path: &[String]) -> String {
+    let segments = path
+        .iter()
+        .map(|segment| format!("{segment:?}"))
+        .collect::<Vec<_>>();
+    format!("[{}]", segments.join(", "))
+}
+
+/// What to do when two paths produce the same output key
+#[derive(Default, Debug, Clone, ValueEnum, Copy)]
+pub enum Collisions {
+    /// Fail before anything is printed or spawned
+    #[default]
+    Error,
+    /// Keep going, with a warning
+    Warn,
+}
+
+impl std::fmt::Display for Collisions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Collisions::Error => write!(f, "error"),
+            Collisions::Warn => write!(f, "warn"),
+        }
+    }
 }
 
+/// What to do with keys that are not valid shell variable names
 #[derive(Default, Debug, Clone, ValueEnum, Copy)]
-pub enum Casing {
+pub enum InvalidKeys {
+    /// Replace every offending character with `_`
     #[default]
-    Insensitive,
-    Lower,
-    Upper,
+    Sanitize,
+    /// Fail on the first invalid key, before printing anything
+    Reject,
 }
 
-impl Casing {
-    fn apply(self, value: &str) -> String {
+impl InvalidKeys {
+    /// Turn `key` into a valid shell variable name.
+    ///
+    /// # Errors
+    ///
+    /// Fails when rejecting an invalid key.
+    pub fn apply(self, key: &str) -> Result<String> {
+        if is_shell_identifier(key) {
+            return Ok(key.to_owned());
+        }
         match self {
-            Casing::Insensitive => value.to_owned(),
-            Casing::Lower => value.to_lowercase(),
-            Casing::Upper => value.to_uppercase(),
+            InvalidKeys::Sanitize => {
+                let mut sanitized: String = key
+                    .chars()
+                    .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+                    .collect();
+                // Identifiers can't start with a digit
+                if !sanitized.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+                    sanitized.insert(0, '_');
+                }
+                Ok(sanitized)
+            }
+            InvalidKeys::Reject => Err(Error::InvalidKey(key.to_owned())),
         }
     }
 }
 
-impl std::fmt::Display for Casing {
+impl std::fmt::Display for InvalidKeys {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Casing::Insensitive => write!(f, "insensitive"),
-            Casing::Lower => write!(f, "lower"),
-            Casing::Upper => write!(f, "upper"),
+            InvalidKeys::Sanitize => write!(f, "sanitize"),
+            InvalidKeys::Reject => write!(f, "reject"),
         }
     }
 }
 
-/// Create a binary template with the given shell and value
-fn binary_template(shell: &str, value: &str) -> String {
-    format!(
-        r#"
-#!{shell}
-echo {value}
-    "#
-    )
+/// Whether `key` matches `[A-Za-z_][A-Za-z0-9_]*`
+fn is_shell_identifier(key: &str) -> bool {
+    key.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
 }
 
-/// drwxr-xr-x
-const EXECUTABLE_UNIX_MODE_BITS: u32 = 0o0_040_755;
+/// How `null` values are rendered
+#[derive(Default, Debug, Clone, ValueEnum, Copy)]
+pub enum Nulls {
+    /// As an empty string
+    #[default]
+    Empty,
+    /// As the `null` literal
+    Literal,
+    /// Not at all
+    Skip,
+}
 
-/// Create a binary file with the given key and value
-fn spawn_binary_at(key: &str, value: &str, output: &OsStr) -> Result<()> {
-    let shell = get_shell();
-    let template = binary_template(&shell, value);
+impl std::fmt::Display for Nulls {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Nulls::Empty => write!(f, "empty"),
+            Nulls::Literal => write!(f, "literal"),
+            Nulls::Skip => write!(f, "skip"),
+        }
+    }
+}
+
+/// Create a POSIX script printing `value` verbatim, no trailing newline added
+fn binary_template(value: &str) -> String {
+    format!("#!/bin/sh\nprintf '%s' {}\n", posix_quote(value))
+}
+
+/// -rwxr-xr-x
+const EXECUTABLE_UNIX_MODE_BITS: u32 = 0o755;
+
+/// Resolve the `--spawn` aliases, '.' is already the current directory
+#[must_use]
+pub fn spawn_dir(path: &OsStr) -> PathBuf {
+    if path == "temp" {
+        std::env::temp_dir()
+    } else {
+        PathBuf::from(path)
+    }
+}
+
+/// Create an executable at `dir/key` that prints `value`, returns its path
+fn spawn_binary_at(key: &str, value: &str, dir: &Path, native: bool) -> Result<PathBuf> {
+    // The key must name a file right inside `dir`, nothing else
+    let is_plain_file_name =
+        !key.is_empty() && key != "." && key != ".." && !key.contains(['/', '\\', '\0']);
+    if !is_plain_file_name {
+        return Err(Error::InvalidSpawnKey(key.to_owned()));
+    }
+
+    let contents = if native {
+        native::executable(value.as_bytes())?
+    } else {
+        binary_template(value).into_bytes()
+    };
+
+    fs::create_dir_all(dir)?;
+    let path = dir.join(key);
+    // Unlink whatever is there first, writing to a symlink would follow it
+    match fs::remove_file(&path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err.into()),
+        _ => {}
+    }
+    // Executable from the start, and never anything we didn't create
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(EXECUTABLE_UNIX_MODE_BITS)
+        .open(&path)?;
+    file.write_all(&contents)?;
+    Ok(path)
+}
+
+/// Shell dialect the environment variables are printed in
+#[derive(Debug, Clone, ValueEnum, Copy)]
+pub enum Shell {
+    Sh,
+    Bash,
+    Zsh,
+    Fish,
+    #[value(alias = "pwsh")]
+    Powershell,
+    #[value(alias = "nu")]
+    Nushell,
+}
+
+impl Shell {
+    /// Guess the dialect from `$SHELL`, falling back to POSIX sh
+    #[must_use]
+    pub fn detect() -> Self {
+        let shell = get_shell();
+        let name = Path::new(&shell)
+            .file_stem()
+            .map(|name| name.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        match name.as_str() {
+            "bash" => Shell::Bash,
+            "zsh" => Shell::Zsh,
+            "fish" => Shell::Fish,
+            "pwsh" | "powershell" => Shell::Powershell,
+            "nu" => Shell::Nushell,
+            _ => Shell::Sh,
+        }
+    }
+
+    /// Statement that sets and exports `key` to exactly `value`
+    #[must_use]
+    pub fn export(self, key: &str, value: &str) -> String {
+        match self {
+            Shell::Sh | Shell::Bash | Shell::Zsh => {
+                format!("{key}={}; export {key};", posix_quote(value))
+            }
+            Shell::Fish => format!("set -gx {key} {};", fish_quote(value)),
+            Shell::Powershell => format!("$env:{key} = {}", powershell_quote(value)),
+            Shell::Nushell => format!("$env.{key} = {}", nushell_quote(value)),
+        }
+    }
+}
+
+impl std::fmt::Display for Shell {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Shell::Sh => write!(f, "sh"),
+            Shell::Bash => write!(f, "bash"),
+            Shell::Zsh => write!(f, "zsh"),
+            Shell::Fish => write!(f, "fish"),
+            Shell::Powershell => write!(f, "powershell"),
+            Shell::Nushell => write!(f, "nushell"),
+        }
+    }
+}
+
+/// Nothing is special inside single quotes but the quote itself
+fn posix_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+/// Fish single quotes only recognize `\'` and `\\` escapes
+fn fish_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\\', r"\\").replace('\'', r"\'"))
+}
 
-    let bin = fs::File::create(output)?;
-    // Set executable permissions
-    let mut perms = bin.metadata()?.permissions();
-    perms.set_mode(EXECUTABLE_UNIX_MODE_BITS);
-    bin.set_permissions(perms)?;
-    fs::write(key, template)?;
-    Ok(())
+/// Powershell single quotes are escaped by doubling them,
+/// and typographic single quotes close them just the same
+fn powershell_quote(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('\'');
+    for c in value.chars() {
+        if matches!(c, '\'' | '\u{2018}' | '\u{2019}' | '\u{201a}' | '\u{201b}') {
+            quoted.push(c);
+        }
+        quoted.push(c);
+    }
+    quoted.push('\'');
+    quoted
 }
 
-/// Print the environment variables to stdout
-fn print_env(key: &str, value: &str) {
-    println!("{key}={value}; export {key};");
+/// Nushell single quotes can't escape anything, use escaped double quotes instead
+fn nushell_quote(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str(r#"\""#),
+            '\\' => quoted.push_str(r"\\"),
+            '\n' => quoted.push_str(r"\n"),
+            '\r' => quoted.push_str(r"\r"),
+            '\t' => quoted.push_str(r"\t"),
+            c if c.is_control() => {
+                let _ = write!(quoted, r"\u{{{:x}}}", u32::from(c));
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
 }
 
 /// The laziest way to get your system shell
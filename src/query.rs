@@ -0,0 +1,521 @@
+//! JSON path queries over a parsed tree, e.g. `$.services[?(@.enabled)].name`
+//!
+//! Supported syntax:
+//!
+//! | Syntax | Selects |
+//! |--------|---------|
+//! | `$` | the root, optional |
+//! | `.key`, `['key']` | a map entry |
+//! | `[0]`, `[-1]` | a sequence element, negative from the end |
+//! | `.*`, `[*]`, `[]` | every child |
+//! | `['a', 'b']`, `[0, 2]` | several children |
+//! | `..key`, `..*` | the node and its descendants, then the selector |
+//! | `[?(expr)]` | every child `expr` holds for |
+//!
+//! Filter expressions compare `@` paths (`@.port`, `@['name']`, `@`) with each
+//! other or with literals (`'text'`, `80`, `true`, `null`) using `==`, `!=`,
+//! `<`, `<=`, `>` or `>=`, and combine with `!`, `&&`, `||` and parentheses.
+//! A bare `@` path holds when it exists and is not `null` or `false`.
+//!
+//! Values are compared by their text, as numbers when both sides are, so
+//! formats without typed scalars (xml, csv, .env) query the same as the others.
+use std::cmp::Ordering;
+
+use crate::tree::{Kind, Node, Scalar, Span};
+use crate::{Error, Result};
+
+#[derive(Debug, Clone, PartialEq)]
+enum Selector {
+    Key(String),
+    Index(i64),
+    Wildcard,
+    Filter(Expr),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Step {
+    Child(Vec<Selector>),
+    Descendant(Vec<Selector>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    Or(Box<Expr>, Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Exists(Vec<Selector>),
+    Compare(Operand, Comparison, Operand),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Operand {
+    /// A path relative to `@`, only keys and indexes
+    Current(Vec<Selector>),
+    Literal(Scalar),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+/// A parsed query
+#[derive(Debug, Clone, PartialEq)]
+pub struct Query {
+    steps: Vec<Step>,
+}
+
+/// A node selected by a query
+#[derive(Debug)]
+pub struct Match<'a> {
+    pub path: Vec<String>,
+    pub node: &'a Node,
+}
+
+impl Query {
+    /// Parse a query, see the [module docs](self) for the syntax.
+    ///
+    /// # Errors
+    ///
+    /// Fails with where the query stops making sense.
+    pub fn parse(query: &str) -> Result<Self> {
+        let mut parser = Parser { query, pos: 0 };
+        let steps = parser.parse_query()?;
+        Ok(Self { steps })
+    }
+
+    /// Every node of `tree` the query selects, sorted by path
+    #[must_use]
+    pub fn select<'a>(&self, tree: &'a Node) -> Vec<Match<'a>> {
+        let mut current = vec![(Vec::new(), tree)];
+        for step in &self.steps {
+            let mut next = Vec::new();
+            for (path, node) in current {
+                match step {
+                    Step::Child(selectors) => apply(selectors, &path, node, &mut next),
+                    Step::Descendant(selectors) => {
+                        for (path, node) in descendants(path, node) {
+                            apply(selectors, &path, node, &mut next);
+                        }
+                    }
+                }
+            }
+            current = next;
+        }
+        let mut matches = current
+            .into_iter()
+            .map(|(path, node)| Match { path, node })
+            .collect::<Vec<_>>();
+        matches.sort_by(|a, b| a.path.cmp(&b.path));
+        // Descendant steps can reach the same node more than one way
+        matches.dedup_by(|a, b| a.path == b.path);
+        matches
+    }
+}
+
+type Selected<'a> = (Vec<String>, &'a Node);
+
+fn apply<'a>(
+    selectors: &[Selector],
+    path: &[String],
+    node: &'a Node,
+    next: &mut Vec<Selected<'a>>,
+) {
+    let mut push = |key: String, child| {
+        let mut path = path.to_vec();
+        path.push(key);
+        next.push((path, child));
+    };
+    for selector in selectors {
+        match selector {
+            Selector::Key(_) | Selector::Index(_) => {
+                if let Some((key, child)) = child(node, selector) {
+                    push(key, child);
+                }
+            }
+            Selector::Wildcard => {
+                for (key, child) in children(node) {
+                    push(key, child);
+                }
+            }
+            Selector::Filter(expr) => {
+                for (key, child) in children(node) {
+                    if expr.holds(child) {
+                        push(key, child);
+                    }
+                }
+            }
+        }
+    }
+}
+
+// The child a key or index selects, along with its path segment
+fn child<'a>(node: &'a Node, selector: &Selector) -> Option<(String, &'a Node)> {
+    match (&node.kind, selector) {
+        (Kind::Map(map), Selector::Key(key)) => map.get(key).map(|child| (key.clone(), child)),
+        (Kind::Seq(seq), Selector::Index(idx)) => {
+            let len = i64::try_from(seq.len()).ok()?;
+            let idx = if *idx < 0 { len + idx } else { *idx };
+            let idx = usize::try_from(idx).ok()?;
+            seq.get(idx).map(|child| (idx.to_string(), child))
+        }
+        _ => None,
+    }
+}
+
+fn children(node: &Node) -> Vec<(String, &Node)> {
+    match &node.kind {
+        Kind::Map(map) => map
+            .iter()
+            .map(|(key, child)| (key.clone(), child))
+            .collect(),
+        Kind::Seq(seq) => seq
+            .iter()
+            .enumerate()
+            .map(|(idx, child)| (idx.to_string(), child))
+            .collect(),
+        Kind::Scalar(_) => Vec::new(),
+    }
+}
+
+// The node itself and everything below it
+fn descendants(path: Vec<String>, node: &Node) -> Vec<Selected<'_>> {
+    let mut found = vec![(path, node)];
+    let mut idx = 0;
+    while let Some((path, node)) = found.get(idx).cloned() {
+        for (key, child) in children(node) {
+            let mut path = path.clone();
+            path.push(key);
+            found.push((path, child));
+        }
+        idx += 1;
+    }
+    found
+}
+
+impl Expr {
+    fn holds(&self, node: &Node) -> bool {
+        match self {
+            Expr::Or(a, b) => a.holds(node) || b.holds(node),
+            Expr::And(a, b) => a.holds(node) && b.holds(node),
+            Expr::Not(expr) => !expr.holds(node),
+            Expr::Exists(selectors) => {
+                resolve(node, selectors).is_some_and(|node| match &node.kind {
+                    Kind::Scalar(scalar) => !matches!(scalar.as_str(), None | Some("false")),
+                    _ => true,
+                })
+            }
+            Expr::Compare(a, comparison, b) => {
+                let (Some(a), Some(b)) = (a.value(node), b.value(node)) else {
+                    return false;
+                };
+                let Some(ordering) = compare(a, b) else {
+                    return *comparison == Comparison::Ne;
+                };
+                match comparison {
+                    Comparison::Eq => ordering == Ordering::Equal,
+                    Comparison::Ne => ordering != Ordering::Equal,
+                    Comparison::Lt => ordering == Ordering::Less,
+                    Comparison::Le => ordering != Ordering::Greater,
+                    Comparison::Gt => ordering == Ordering::Greater,
+                    Comparison::Ge => ordering != Ordering::Less,
+                }
+            }
+        }
+    }
+}
+
+impl Operand {
+    // Only scalars compare, maps and sequences don't have a value
+    fn value<'a>(&'a self, node: &'a Node) -> Option<&'a Scalar> {
+        match self {
+            Operand::Literal(scalar) => Some(scalar),
+            Operand::Current(selectors) => match &resolve(node, selectors)?.kind {
+                Kind::Scalar(scalar) => Some(scalar),
+                _ => None,
+            },
+        }
+    }
+}
+
+fn resolve<'a>(node: &'a Node, selectors: &[Selector]) -> Option<&'a Node> {
+    selectors.iter().try_fold(node, |node, selector| {
+        child(node, selector).map(|(_, child)| child)
+    })
+}
+
+/// Numerically when both sides are numbers, by their text otherwise
+fn compare(a: &Scalar, b: &Scalar) -> Option<Ordering> {
+    match (a.as_str(), b.as_str()) {
+        (None, None) => Some(Ordering::Equal),
+        (Some(a), Some(b)) => match (a.parse::<f64>(), b.parse::<f64>()) {
+            (Ok(a), Ok(b)) => a.partial_cmp(&b),
+            _ => Some(a.cmp(b)),
+        },
+        _ => None,
+    }
+}
+
+struct Parser<'a> {
+    query: &'a str,
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn rest(&self) -> &str {
+        &self.query[self.pos..]
+    }
+
+    fn err(&self, message: &str) -> Error {
+        Error::InvalidQuery(
+            message.to_owned(),
+            Span {
+                line: 1,
+                column: self.query[..self.pos].chars().count() + 1,
+            },
+        )
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = self.rest();
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    fn eat(&mut self, token: &str) -> bool {
+        self.skip_whitespace();
+        if self.rest().starts_with(token) {
+            self.pos += token.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, token: &str) -> Result<()> {
+        if self.eat(token) {
+            Ok(())
+        } else {
+            Err(self.err(&format!("expected `{token}`")))
+        }
+    }
+
+    fn parse_query(&mut self) -> Result<Vec<Step>> {
+        if self.eat("$") || self.rest().starts_with(['.', '[']) {
+            return self.parse_steps(Vec::new());
+        }
+        // Without the leading `$` or `.`, e.g. `services.name`
+        let name = self.parse_name()?;
+        self.parse_steps(vec![Step::Child(vec![Selector::Key(name)])])
+    }
+
+    fn parse_steps(&mut self, mut steps: Vec<Step>) -> Result<Vec<Step>> {
+        loop {
+            self.skip_whitespace();
+            if self.rest().is_empty() {
+                return Ok(steps);
+            }
+            if self.eat("..") {
+                let selectors = if self.rest().starts_with('[') {
+                    self.parse_brackets()?
+                } else {
+                    vec![self.parse_dotted()?]
+                };
+                steps.push(Step::Descendant(selectors));
+            } else if self.eat(".") {
+                // jq style `.[0]`
+                if self.rest().starts_with('[') {
+                    continue;
+                }
+                steps.push(Step::Child(vec![self.parse_dotted()?]));
+            } else if self.rest().starts_with('[') {
+                steps.push(Step::Child(self.parse_brackets()?));
+            } else {
+                return Err(self.err("expected `.` or `[`"));
+            }
+        }
+    }
+
+    // What follows a `.`, a name or `*`
+    fn parse_dotted(&mut self) -> Result<Selector> {
+        if self.eat("*") {
+            Ok(Selector::Wildcard)
+        } else {
+            self.parse_name().map(Selector::Key)
+        }
+    }
+
+    fn parse_name(&mut self) -> Result<String> {
+        let rest = self.rest();
+        let len = rest
+            .find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '-' || c == '@'))
+            .unwrap_or(rest.len());
+        if len == 0 {
+            return Err(self.err("expected a key"));
+        }
+        let name = rest[..len].to_owned();
+        self.pos += len;
+        Ok(name)
+    }
+
+    fn parse_brackets(&mut self) -> Result<Vec<Selector>> {
+        self.expect("[")?;
+        if self.eat("]") {
+            return Ok(vec![Selector::Wildcard]);
+        }
+        if self.eat("?") {
+            self.expect("(")?;
+            let expr = self.parse_or()?;
+            self.expect(")")?;
+            self.expect("]")?;
+            return Ok(vec![Selector::Filter(expr)]);
+        }
+        let mut selectors = Vec::new();
+        loop {
+            selectors.push(if self.eat("*") {
+                Selector::Wildcard
+            } else {
+                self.parse_subscript()?
+            });
+            if self.eat("]") {
+                return Ok(selectors);
+            }
+            self.expect(",")?;
+        }
+    }
+
+    // A quoted key or an index
+    fn parse_subscript(&mut self) -> Result<Selector> {
+        self.skip_whitespace();
+        if self.rest().starts_with(['\'', '"']) {
+            return self.parse_string().map(Selector::Key);
+        }
+        let rest = self.rest();
+        let len = rest
+            .char_indices()
+            .find(|&(idx, c)| !(c.is_ascii_digit() || (idx == 0 && c == '-')))
+            .map_or(rest.len(), |(idx, _)| idx);
+        let index = rest[..len]
+            .parse()
+            .map_err(|_| self.err("expected a quoted key or an index"))?;
+        self.pos += len;
+        Ok(Selector::Index(index))
+    }
+
+    fn parse_string(&mut self) -> Result<String> {
+        let quote = self.rest().chars().next().unwrap_or('\'');
+        let mut text = String::new();
+        let mut chars = self.query[self.pos + 1..].char_indices();
+        while let Some((idx, c)) = chars.next() {
+            match c {
+                '\\' => {
+                    let Some((_, escaped)) = chars.next() else {
+                        break;
+                    };
+                    text.push(escaped);
+                }
+                c if c == quote => {
+                    self.pos += idx + 2;
+                    return Ok(text);
+                }
+                c => text.push(c),
+            }
+        }
+        Err(self.err("unterminated string"))
+    }
+
+    fn parse_or(&mut self) -> Result<Expr> {
+        let mut expr = self.parse_and()?;
+        while self.eat("||") {
+            expr = Expr::Or(Box::new(expr), Box::new(self.parse_and()?));
+        }
+        Ok(expr)
+    }
+
+    fn parse_and(&mut self) -> Result<Expr> {
+        let mut expr = self.parse_unary()?;
+        while self.eat("&&") {
+            expr = Expr::And(Box::new(expr), Box::new(self.parse_unary()?));
+        }
+        Ok(expr)
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr> {
+        if self.eat("!") {
+            return Ok(Expr::Not(Box::new(self.parse_unary()?)));
+        }
+        if self.eat("(") {
+            let expr = self.parse_or()?;
+            self.expect(")")?;
+            return Ok(expr);
+        }
+        let left = self.parse_operand()?;
+        let Some(comparison) = self.parse_comparison() else {
+            return match left {
+                Operand::Current(selectors) => Ok(Expr::Exists(selectors)),
+                Operand::Literal(_) => Err(self.err("expected a comparison")),
+            };
+        };
+        let right = self.parse_operand()?;
+        Ok(Expr::Compare(left, comparison, right))
+    }
+
+    fn parse_comparison(&mut self) -> Option<Comparison> {
+        // Longest first, so `<=` isn't read as `<`
+        [
+            ("==", Comparison::Eq),
+            ("!=", Comparison::Ne),
+            ("<=", Comparison::Le),
+            (">=", Comparison::Ge),
+            ("<", Comparison::Lt),
+            (">", Comparison::Gt),
+        ]
+        .into_iter()
+        .find_map(|(token, comparison)| self.eat(token).then_some(comparison))
+    }
+
+    fn parse_operand(&mut self) -> Result<Operand> {
+        self.skip_whitespace();
+        if self.eat("@") {
+            let mut selectors = Vec::new();
+            loop {
+                if self.rest().starts_with('[') {
+                    self.pos += 1;
+                    selectors.push(self.parse_subscript()?);
+                    self.expect("]")?;
+                } else if self.rest().starts_with('.') {
+                    self.pos += 1;
+                    selectors.push(Selector::Key(self.parse_name()?));
+                } else {
+                    return Ok(Operand::Current(selectors));
+                }
+            }
+        }
+        if self.rest().starts_with(['\'', '"']) {
+            return Ok(Operand::Literal(Scalar::String(self.parse_string()?)));
+        }
+        for (word, scalar) in [
+            ("true", Scalar::Bool(true)),
+            ("false", Scalar::Bool(false)),
+            ("null", Scalar::Null),
+        ] {
+            if self.eat(word) {
+                return Ok(Operand::Literal(scalar));
+            }
+        }
+        let rest = self.rest();
+        let len = rest
+            .find(|c: char| !(c.is_ascii_digit() || matches!(c, '-' | '+' | '.' | 'e' | 'E')))
+            .unwrap_or(rest.len());
+        if len == 0 || rest[..len].parse::<f64>().is_err() {
+            return Err(self.err("expected `@`, a string, a number, `true`, `false` or `null`"));
+        }
+        let number = rest[..len].to_owned();
+        self.pos += len;
+        Ok(Operand::Literal(Scalar::Number(number)))
+    }
+}
@@ -0,0 +1,318 @@
+//! Match template patterns, with `*` and `**` wildcards
+use std::collections::BTreeMap;
+use std::sync::LazyLock;
+
+use regex::Regex;
+
+use crate::tree::{Kind, Node, Scalar};
+use crate::{Error, Result};
+
+/// `path=$VAR` items of a path form template, e.g. `servers.*.host=$HOST_{}`
+static PATH_ITEM: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^([^=\s]+)\s*=\s*\$\s*((?:\w|\*|\{\})+(?::-\S*|!)?)$").unwrap());
+
+/// Where matched keys and indexes go in a variable name, in order
+const PLACEHOLDERS: [&str; 2] = ["*", "{}"];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Key(String),
+    /// `*`, any single key or index
+    Any,
+    /// `**`, any number of keys or indexes, none included
+    AnyDepth,
+}
+
+/// What a variable gets when its path is missing from the data
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Missing {
+    /// `$VAR`, left out
+    Skip,
+    /// `$VAR:-value`
+    Default(String),
+    /// `$VAR!`, an error
+    Required,
+}
+
+/// A template variable along with the path it binds
+#[derive(Debug, Clone)]
+pub struct Pattern {
+    segments: Vec<Segment>,
+    variable: String,
+    missing: Missing,
+}
+
+/// A node bound by a pattern
+#[derive(Debug)]
+pub struct Match<'a> {
+    pub path: Vec<String>,
+    pub node: &'a Node,
+    /// The variable name, with placeholders replaced by what the wildcards matched
+    pub variable: String,
+}
+
+impl Pattern {
+    /// One pattern per variable of a parsed template.
+    ///
+    /// Map keys `*` and `**` are wildcards. Sequence positions are too when the
+    /// variable has placeholders, so `[{"host": $HOST_*}]` binds every element.
+    ///
+    /// # Errors
+    ///
+    /// Fails when a template leaf is not a variable, `null` that is.
+    pub fn from_template(template: &Node) -> Result<Vec<Self>> {
+        let mut patterns = Vec::new();
+        collect(template, &mut Vec::new(), &mut patterns)?;
+        Ok(patterns)
+    }
+
+    /// Every node of `tree` this pattern binds, sorted by path. `**` matches are
+    /// substituted with their keys chained by `separator`.
+    #[must_use]
+    pub fn matches<'a>(&self, tree: &'a Node, separator: char) -> Vec<Match<'a>> {
+        let mut found = Vec::new();
+        walk(
+            tree,
+            &self.segments,
+            &mut Vec::new(),
+            &mut Vec::new(),
+            &mut found,
+        );
+        let mut matches = found
+            .into_iter()
+            .map(|(path, node, captures)| Match {
+                variable: substitute(&self.variable, &captures, separator),
+                path,
+                node,
+            })
+            .collect::<Vec<_>>();
+        matches.sort_by(|a, b| a.path.cmp(&b.path));
+        // `**` can reach the same node more than one way
+        matches.dedup_by(|a, b| a.path == b.path);
+        matches
+    }
+
+    /// The variable name, without its default or required marker
+    #[must_use]
+    pub fn variable(&self) -> &str {
+        &self.variable
+    }
+
+    #[must_use]
+    pub fn missing(&self) -> &Missing {
+        &self.missing
+    }
+
+    /// The bound path as written, wildcards included
+    #[must_use]
+    pub fn path(&self) -> Vec<String> {
+        self.segments
+            .iter()
+            .map(|segment| match segment {
+                Segment::Key(key) => key.clone(),
+                Segment::Any => "*".to_owned(),
+                Segment::AnyDepth => "**".to_owned(),
+            })
+            .collect()
+    }
+}
+
+// Split `VAR:-value` and `VAR!` into the name and what to do when missing
+fn split_marker(variable: &str) -> (&str, Missing) {
+    if let Some(name) = variable.strip_suffix('!') {
+        (name, Missing::Required)
+    } else if let Some((name, default)) = variable.split_once(":-") {
+        (name, Missing::Default(default.to_owned()))
+    } else {
+        (variable, Missing::Skip)
+    }
+}
+
+fn collect(
+    node: &Node,
+    path: &mut Vec<(Segment, bool)>,
+    patterns: &mut Vec<Pattern>,
+) -> Result<()> {
+    match &node.kind {
+        Kind::Map(map) => {
+            for (key, child) in map {
+                let segment = match key.as_str() {
+                    "*" => Segment::Any,
+                    "**" => Segment::AnyDepth,
+                    _ => Segment::Key(key.clone()),
+                };
+                path.push((segment, false));
+                collect(child, path, patterns)?;
+                path.pop();
+            }
+        }
+        Kind::Seq(seq) => {
+            for (idx, child) in seq.iter().enumerate() {
+                path.push((Segment::Key(idx.to_string()), true));
+                collect(child, path, patterns)?;
+                path.pop();
+            }
+        }
+        // A placeholder for positions that aren't extracted
+        Kind::Scalar(Scalar::String(skipped)) if skipped == "_" => {}
+        Kind::Scalar(scalar) => {
+            let Some(variable) = scalar.as_str() else {
+                let keys = path.iter().map(|(segment, _)| match segment {
+                    Segment::Key(key) => key.as_str(),
+                    Segment::Any => "*",
+                    Segment::AnyDepth => "**",
+                });
+                return Err(Error::InvalidMatchTemplate(
+                    format!(
+                        "Template value for `{}` must be a variable name",
+                        keys.collect::<Vec<_>>().join(".")
+                    ),
+                    None,
+                ));
+            };
+            let (variable, missing) = split_marker(variable);
+            let wild = PLACEHOLDERS.iter().any(|p| variable.contains(p));
+            let segments = path
+                .iter()
+                .map(|(segment, positional)| {
+                    if *positional && wild {
+                        Segment::Any
+                    } else {
+                        segment.clone()
+                    }
+                })
+                .collect();
+            patterns.push(Pattern {
+                segments,
+                variable: variable.to_owned(),
+                missing,
+            });
+        }
+    }
+    Ok(())
+}
+
+// Children of a node along with their key or index
+fn children(node: &Node) -> Vec<(String, &Node)> {
+    match &node.kind {
+        Kind::Map(map) => map
+            .iter()
+            .map(|(key, child)| (key.clone(), child))
+            .collect(),
+        Kind::Seq(seq) => seq
+            .iter()
+            .enumerate()
+            .map(|(idx, child)| (idx.to_string(), child))
+            .collect(),
+        Kind::Scalar(_) => Vec::new(),
+    }
+}
+
+// A matched path and node, along with what each wildcard matched on the way
+type Found<'a> = (Vec<String>, &'a Node, Vec<Vec<String>>);
+
+fn walk<'a>(
+    node: &'a Node,
+    segments: &[Segment],
+    path: &mut Vec<String>,
+    captures: &mut Vec<Vec<String>>,
+    found: &mut Vec<Found<'a>>,
+) {
+    let Some((segment, rest)) = segments.split_first() else {
+        found.push((path.clone(), node, captures.clone()));
+        return;
+    };
+    match segment {
+        Segment::Key(key) => {
+            if let Some(child) = node.get(std::slice::from_ref(key)) {
+                path.push(key.clone());
+                walk(child, rest, path, captures, found);
+                path.pop();
+            }
+        }
+        Segment::Any => {
+            for (key, child) in children(node) {
+                path.push(key.clone());
+                captures.push(vec![key]);
+                walk(child, rest, path, captures, found);
+                captures.pop();
+                path.pop();
+            }
+        }
+        Segment::AnyDepth => {
+            let mut descendants = vec![(Vec::new(), node)];
+            let mut idx = 0;
+            while let Some((relative, descendant)) = descendants.get(idx).cloned() {
+                for (key, child) in children(descendant) {
+                    let mut relative = relative.clone();
+                    relative.push(key);
+                    descendants.push((relative, child));
+                }
+                idx += 1;
+            }
+            for (relative, descendant) in descendants {
+                let depth = path.len();
+                path.extend(relative.iter().cloned());
+                captures.push(relative);
+                walk(descendant, rest, path, captures, found);
+                captures.pop();
+                path.truncate(depth);
+            }
+        }
+    }
+}
+
+/// Replace each placeholder of `variable` with the next capture, in order
+fn substitute(variable: &str, captures: &[Vec<String>], separator: char) -> String {
+    let mut captures = captures
+        .iter()
+        .map(|capture| capture.join(&separator.to_string()));
+    let mut substituted = String::with_capacity(variable.len());
+    let mut rest = variable;
+    while let Some((idx, placeholder)) = PLACEHOLDERS
+        .iter()
+        .filter_map(|p| rest.find(p).map(|idx| (idx, p)))
+        .min()
+    {
+        substituted.push_str(&rest[..idx]);
+        substituted.push_str(&captures.next().unwrap_or_default());
+        rest = &rest[idx + placeholder.len()..];
+    }
+    substituted.push_str(rest);
+    substituted
+}
+
+/// Parse a path form template, `path=$VAR` items split by newlines or `;`,
+/// with paths chained by `separator`. `None` when `template` is not one.
+#[must_use]
+pub fn from_paths(template: &str, separator: char) -> Option<Node> {
+    let mut root = BTreeMap::new();
+    let items = template
+        .split(['\n', ';'])
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .collect::<Vec<_>>();
+    if items.is_empty() {
+        return None;
+    }
+    for item in items {
+        let captures = PATH_ITEM.captures(item)?;
+        let mut keys = captures[1].split(separator).collect::<Vec<_>>();
+        let last = keys.pop()?;
+        let mut map = &mut root;
+        for key in keys {
+            let node = map
+                .entry(key.to_owned())
+                .or_insert_with(|| Node::map(BTreeMap::new()));
+            let Kind::Map(child) = &mut node.kind else {
+                return None;
+            };
+            map = child;
+        }
+        map.insert(
+            last.to_owned(),
+            Scalar::String(captures[2].to_owned()).into(),
+        );
+    }
+    Some(Node::map(root))
+}
@@ -0,0 +1,135 @@
+use std::io::Read;
+use std::ops::Range;
+
+use ::toml::Value as TomlValue;
+use toml_edit::{ImDocument, Item, Table, Value};
+
+use crate::convert::Number;
+use crate::error::span_at;
+use crate::tree::{Kind, Node, Scalar};
+use crate::{Error, Result};
+
+/// Parse a toml document, every value spanning from where it is written
+///
+/// # Errors
+///
+/// Fails when the input is not valid toml.
+pub fn from_str(input: &str) -> Result<Node> {
+    let document = ImDocument::parse(input)?;
+    Ok(table_into_node(document.as_table(), input))
+}
+
+/// See [`from_str`]
+///
+/// # Errors
+///
+/// Fails when the input is not valid toml, or can't be read.
+pub fn from_reader(mut read: impl Read) -> Result<Node> {
+    // The toml parser needs the whole document at once
+    let mut input = String::new();
+    read.read_to_string(&mut input)?;
+    from_str(&input)
+}
+
+/// Serialize a tree as a toml document
+///
+/// # Errors
+///
+/// Fails when the tree is not a table, or holds something toml can't, like
+/// `null` or integers that don't fit in 64 signed bits.
+pub fn to_string(node: &Node) -> Result<String> {
+    match from_node(node)? {
+        TomlValue::Table(table) => Ok(::toml::to_string_pretty(&table)?),
+        _ => Err(Error::Unconvertible(
+            "Can't convert to toml, only tables can be".to_owned(),
+        )),
+    }
+}
+
+fn from_node(node: &Node) -> Result<TomlValue> {
+    Ok(match &node.kind {
+        // Leaving it out would shift every later array index
+        Kind::Scalar(Scalar::Null) => {
+            return Err(Error::Unconvertible(
+                "Can't convert null to toml, pass --null empty or --null skip".to_owned(),
+            ))
+        }
+        Kind::Scalar(Scalar::Bool(bool)) => TomlValue::Boolean(*bool),
+        Kind::Scalar(Scalar::Number(number)) => match Number::parse(number) {
+            Some(Number::Int(int)) => TomlValue::Integer(int),
+            // Toml integers are signed 64 bits, a float would silently round it
+            Some(Number::Uint(_)) => {
+                return Err(Error::Unconvertible(format!(
+                    "Can't convert {number} to toml, integers must fit in 64 signed bits"
+                )))
+            }
+            Some(Number::Float(float)) => TomlValue::Float(float),
+            None => TomlValue::String(number.clone()),
+        },
+        Kind::Scalar(Scalar::String(string)) => TomlValue::String(string.clone()),
+        Kind::Seq(seq) => TomlValue::Array(seq.iter().map(from_node).collect::<Result<_>>()?),
+        Kind::Map(map) => TomlValue::Table(
+            map.iter()
+                .map(|(key, node)| Ok((key.clone(), from_node(node)?)))
+                .collect::<Result<_>>()?,
+        ),
+    })
+}
+
+fn table_into_node(table: &Table, input: &str) -> Node {
+    let map = table
+        .iter()
+        .map(|(key, item)| (key.to_owned(), item_into_node(item, input)))
+        .collect();
+    spanned(Node::map(map), table.span(), input)
+}
+
+fn item_into_node(item: &Item, input: &str) -> Node {
+    match item {
+        Item::Value(value) => value_into_node(value, input),
+        Item::Table(table) => table_into_node(table, input),
+        Item::ArrayOfTables(tables) => spanned(
+            Node::seq(
+                tables
+                    .iter()
+                    .map(|table| table_into_node(table, input))
+                    .collect(),
+            ),
+            tables.span(),
+            input,
+        ),
+        Item::None => Scalar::Null.into(),
+    }
+}
+
+fn value_into_node(value: &Value, input: &str) -> Node {
+    let node = match value {
+        Value::String(string) => Scalar::String(string.value().clone()).into(),
+        Value::Integer(int) => Scalar::Number(int.value().to_string()).into(),
+        Value::Float(float) => Scalar::from_f64(*float.value()).into(),
+        Value::Boolean(bool) => Scalar::Bool(*bool.value()).into(),
+        // Keep datetimes exactly as written
+        Value::Datetime(datetime) => Scalar::String(datetime.value().to_string()).into(),
+        Value::Array(array) => Node::seq(
+            array
+                .iter()
+                .map(|value| value_into_node(value, input))
+                .collect(),
+        ),
+        Value::InlineTable(table) => Node::map(
+            table
+                .iter()
+                .map(|(key, value)| (key.to_owned(), value_into_node(value, input)))
+                .collect(),
+        ),
+    };
+    spanned(node, value.span(), input)
+}
+
+// Tables made up by dotted keys or headers of nested tables have no span
+fn spanned(node: Node, range: Option<Range<usize>>, input: &str) -> Node {
+    match range.and_then(|range| span_at(input, range.start)) {
+        Some(span) => node.with_span(span),
+        None => node,
+    }
+}
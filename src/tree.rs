@@ -0,0 +1,163 @@
+//! Format agnostic tree every parser produces and everything else consumes
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+/// A node of a parsed document, along with where it was found in the source
+#[derive(Debug, Clone, PartialEq)]
+pub struct Node {
+    pub kind: Kind,
+    /// Only set by parsers that report positions: toml, xml, csv and .env.
+    /// Json and yaml are parsed through serde, which doesn't, so their nodes
+    /// have none
+    pub span: Option<Span>,
+    /// Name of the input the node was read from, e.g. a file path or `<stdin>`
+    pub source: Option<Arc<str>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Kind {
+    Map(BTreeMap<String, Node>),
+    Seq(Vec<Node>),
+    Scalar(Scalar),
+}
+
+/// A leaf value
+#[derive(Debug, Clone, PartialEq)]
+pub enum Scalar {
+    Null,
+    Bool(bool),
+    /// Kept as written so no precision is lost, e.g. `0.30000000000000000004`
+    Number(String),
+    String(String),
+}
+
+/// 1-based position of a node in its source
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Node {
+    #[must_use]
+    pub fn new(kind: Kind) -> Self {
+        Self {
+            kind,
+            span: None,
+            source: None,
+        }
+    }
+
+    #[must_use]
+    pub fn map(map: BTreeMap<String, Node>) -> Self {
+        Self::new(Kind::Map(map))
+    }
+
+    #[must_use]
+    pub fn seq(seq: Vec<Node>) -> Self {
+        Self::new(Kind::Seq(seq))
+    }
+
+    #[must_use]
+    pub fn scalar(scalar: Scalar) -> Self {
+        Self::new(Kind::Scalar(scalar))
+    }
+
+    #[must_use]
+    pub fn with_span(self, span: Span) -> Self {
+        Self {
+            span: Some(span),
+            ..self
+        }
+    }
+
+    /// Tag the node and everything below it as read from `source`
+    pub fn set_source(&mut self, source: &Arc<str>) {
+        self.source = Some(Arc::clone(source));
+        match &mut self.kind {
+            Kind::Map(map) => map.values_mut().for_each(|node| node.set_source(source)),
+            Kind::Seq(seq) => seq.iter_mut().for_each(|node| node.set_source(source)),
+            Kind::Scalar(_) => {}
+        }
+    }
+
+    /// The node at `path`, sequences are indexed by position
+    #[must_use]
+    pub fn get(&self, path: &[String]) -> Option<&Node> {
+        path.iter()
+            .try_fold(self, |node, segment| match &node.kind {
+                Kind::Map(map) => map.get(segment),
+                Kind::Seq(seq) => seq.get(segment.parse::<usize>().ok()?),
+                Kind::Scalar(_) => None,
+            })
+    }
+
+    /// Every scalar leaf along with the path leading to it, sorted by path.
+    ///
+    /// Empty maps and sequences have no leaves, a scalar root is a leaf at the empty path.
+    #[must_use]
+    pub fn leaves(&self) -> Vec<(Vec<String>, &Node, &Scalar)> {
+        let mut leaves = Vec::new();
+        self.dfs_leaves(&mut Vec::new(), &mut leaves);
+        leaves.sort_by(|(a, ..), (b, ..)| a.cmp(b));
+        leaves
+    }
+
+    fn dfs_leaves<'a>(
+        &'a self,
+        path: &mut Vec<String>,
+        leaves: &mut Vec<(Vec<String>, &'a Node, &'a Scalar)>,
+    ) {
+        match &self.kind {
+            Kind::Map(map) => {
+                for (key, node) in map {
+                    path.push(key.clone());
+                    node.dfs_leaves(path, leaves);
+                    path.pop();
+                }
+            }
+            Kind::Seq(seq) => {
+                for (idx, node) in seq.iter().enumerate() {
+                    path.push(idx.to_string());
+                    node.dfs_leaves(path, leaves);
+                    path.pop();
+                }
+            }
+            Kind::Scalar(scalar) => leaves.push((path.clone(), self, scalar)),
+        }
+    }
+}
+
+impl From<Scalar> for Node {
+    fn from(scalar: Scalar) -> Self {
+        Node::scalar(scalar)
+    }
+}
+
+impl Scalar {
+    /// Textual form of the value, `None` for `null`
+    #[must_use]
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Scalar::Null => None,
+            Scalar::Bool(true) => Some("true"),
+            Scalar::Bool(false) => Some("false"),
+            Scalar::Number(text) | Scalar::String(text) => Some(text),
+        }
+    }
+
+    /// Floats that have no number literal (`NaN`, `inf`) are kept as strings
+    #[must_use]
+    pub fn from_f64(float: f64) -> Self {
+        serde_json::Number::from_f64(float).map_or_else(
+            || Scalar::String(float.to_string()),
+            |number| Scalar::Number(number.to_string()),
+        )
+    }
+}
+
+impl std::fmt::Display for Span {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}, column {}", self.line, self.column)
+    }
+}
@@ -0,0 +1,106 @@
+use roxmltree::{Document, Node as XmlNode};
+use std::collections::BTreeMap;
+use std::io::Read;
+
+use crate::tree::{Node, Scalar, Span};
+use crate::Result;
+
+/// Key holding the text of elements that also have attributes or children
+pub const TEXT_KEY: &str = "#text";
+
+/// Parse a xml document into a tree rooted at the root element name.
+///
+/// Attributes become keys prefixed with `attribute_prefix`, repeated sibling
+/// elements become arrays, and elements with neither attributes nor children
+/// become their text.
+///
+/// # Errors
+///
+/// Fails when the input is not valid xml.
+pub fn from_str(input: &str, attribute_prefix: &str) -> Result<Node> {
+    let document = Document::parse(input)?;
+    let root = document.root_element();
+    let mut map = BTreeMap::new();
+    map.insert(
+        root.tag_name().name().to_owned(),
+        element_into_node(&document, root, attribute_prefix),
+    );
+    Ok(Node::map(map))
+}
+
+/// See [`from_str`]
+///
+/// # Errors
+///
+/// Fails when the input is not valid xml, or can't be read.
+pub fn from_reader(mut read: impl Read, attribute_prefix: &str) -> Result<Node> {
+    // The xml parser needs the whole document at once
+    let mut input = String::new();
+    read.read_to_string(&mut input)?;
+    from_str(&input, attribute_prefix)
+}
+
+fn element_into_node(document: &Document, element: XmlNode, attribute_prefix: &str) -> Node {
+    let span = span_at(document, element.range().start);
+    let mut map = BTreeMap::new();
+    for attribute in element.attributes() {
+        map.insert(
+            format!("{attribute_prefix}{}", attribute.name()),
+            Node::scalar(Scalar::String(attribute.value().to_owned()))
+                .with_span(span_at(document, attribute.range().start)),
+        );
+    }
+
+    // Group child elements by name, keeping document order inside each group
+    let mut children: BTreeMap<&str, Vec<Node>> = BTreeMap::new();
+    // Text segments split by child elements are trimmed and joined by a space
+    let mut text = Vec::new();
+    let mut text_span = None;
+    for child in element.children() {
+        if child.is_element() {
+            children
+                .entry(child.tag_name().name())
+                .or_default()
+                .push(element_into_node(document, child, attribute_prefix));
+        } else if child.is_text() {
+            let segment = child.text().unwrap_or_default().trim();
+            if !segment.is_empty() {
+                text_span.get_or_insert_with(|| span_at(document, child.range().start));
+                text.push(segment);
+            }
+        }
+    }
+    let text = text.join(" ");
+
+    if map.is_empty() && children.is_empty() {
+        return Node::scalar(Scalar::String(text)).with_span(span);
+    }
+    for (name, mut nodes) in children {
+        let node = if nodes.len() == 1 {
+            nodes.remove(0)
+        } else {
+            // A group starts where its first element does
+            let span = nodes[0].span;
+            Node {
+                span,
+                ..Node::seq(nodes)
+            }
+        };
+        map.insert(name.to_owned(), node);
+    }
+    if let Some(text_span) = text_span {
+        map.insert(
+            TEXT_KEY.to_owned(),
+            Node::scalar(Scalar::String(text)).with_span(text_span),
+        );
+    }
+    Node::map(map).with_span(span)
+}
+
+fn span_at(document: &Document, pos: usize) -> Span {
+    let pos = document.text_pos_at(pos);
+    Span {
+        line: pos.row as usize,
+        column: pos.col as usize,
+    }
+}
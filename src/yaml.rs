@@ -0,0 +1,110 @@
+use serde::Deserialize;
+use serde_yaml::Value as YamlValue;
+use std::io::Read;
+
+use crate::convert::Number;
+use crate::tree::{Kind, Node, Scalar};
+use crate::{json, Result};
+
+/// See [`from_reader`]
+///
+/// # Errors
+///
+/// Fails when the input is not valid yaml.
+pub fn from_str(input: &str) -> Result<Node> {
+    from_reader(input.as_bytes())
+}
+
+/// Read every document in the stream, resolving aliases and `<<` merge keys.
+///
+/// A single document is returned as is, multiple documents are returned as an
+/// array so they get flattened as `0.foo`, `1.foo`, ...
+///
+/// # Errors
+///
+/// Fails when the input is not valid yaml.
+pub fn from_reader(read: impl Read) -> Result<Node> {
+    let mut documents = Vec::new();
+    for document in serde_yaml::Deserializer::from_reader(read) {
+        let mut value = YamlValue::deserialize(document)?;
+        value.apply_merge()?;
+        documents.push(into_node(value));
+    }
+    Ok(match documents.len() {
+        0 => Scalar::Null.into(),
+        1 => documents.remove(0),
+        _ => Node::seq(documents),
+    })
+}
+
+/// Serialize a tree as a yaml document
+///
+/// # Errors
+///
+/// Fails when the tree can't be serialized.
+pub fn to_string(node: &Node) -> Result<String> {
+    Ok(serde_yaml::to_string(&from_node(node))?)
+}
+
+fn from_node(node: &Node) -> YamlValue {
+    match &node.kind {
+        Kind::Scalar(Scalar::Null) => YamlValue::Null,
+        Kind::Scalar(Scalar::Bool(bool)) => YamlValue::Bool(*bool),
+        Kind::Scalar(Scalar::Number(number)) => match Number::parse(number) {
+            Some(Number::Int(int)) => YamlValue::from(int),
+            Some(Number::Uint(uint)) => YamlValue::from(uint),
+            Some(Number::Float(float)) => YamlValue::from(float),
+            None => YamlValue::String(number.clone()),
+        },
+        Kind::Scalar(Scalar::String(string)) => YamlValue::String(string.clone()),
+        Kind::Seq(seq) => YamlValue::Sequence(seq.iter().map(from_node).collect()),
+        Kind::Map(map) => YamlValue::Mapping(
+            map.iter()
+                .map(|(key, node)| (YamlValue::String(key.clone()), from_node(node)))
+                .collect(),
+        ),
+    }
+}
+
+fn into_node(value: YamlValue) -> Node {
+    match value {
+        YamlValue::Null => Scalar::Null.into(),
+        YamlValue::Bool(bool) => Scalar::Bool(bool).into(),
+        YamlValue::Number(number) => {
+            if let Some(int) = number.as_i64() {
+                Scalar::Number(int.to_string()).into()
+            } else if let Some(uint) = number.as_u64() {
+                Scalar::Number(uint.to_string()).into()
+            } else {
+                number
+                    .as_f64()
+                    .map_or_else(|| Scalar::String(number.to_string()), Scalar::from_f64)
+                    .into()
+            }
+        }
+        YamlValue::String(string) => Scalar::String(string).into(),
+        YamlValue::Sequence(sequence) => Node::seq(sequence.into_iter().map(into_node).collect()),
+        YamlValue::Mapping(mapping) => Node::map(
+            mapping
+                .into_iter()
+                .map(|(key, value)| (key_to_string(key), into_node(value)))
+                .collect(),
+        ),
+        // Tags carry no meaning for us, keep whatever they annotate
+        YamlValue::Tagged(tagged) => into_node(tagged.value),
+    }
+}
+
+// Yaml allows any node as a mapping key, we only care about its textual form
+fn key_to_string(key: YamlValue) -> String {
+    match key {
+        YamlValue::Null => "null".to_owned(),
+        YamlValue::Bool(bool) => bool.to_string(),
+        YamlValue::Number(number) => number.to_string(),
+        YamlValue::String(string) => string,
+        YamlValue::Tagged(tagged) => key_to_string(tagged.value),
+        complex @ (YamlValue::Sequence(_) | YamlValue::Mapping(_)) => {
+            json::to_compact_string(&into_node(complex))
+        }
+    }
+}
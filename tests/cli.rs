@@ -1,10 +1,24 @@
-use assert_cmd::{crate_name, Command};
+use assert_cmd::Command;
 use assert_fs::prelude::*;
 use predicates::prelude::*;
-use std::env;
 
 pub fn cmd() -> Command {
-    Command::cargo_bin(crate_name!()).unwrap()
+    let mut cmd = Command::cargo_bin("picnic").unwrap();
+    // Keep the output dialect independent of whoever runs the tests
+    cmd.env("SHELL", "/bin/sh");
+    cmd
+}
+
+/// [`cmd`] run from a fresh directory holding `files`, as `(name, content)`.
+/// The directory is removed when the returned handle is dropped.
+pub fn cmd_with_files(files: &[(&str, &str)]) -> (Command, assert_fs::TempDir) {
+    let dir = assert_fs::TempDir::new().unwrap();
+    for (name, content) in files {
+        dir.child(name).write_str(content).unwrap();
+    }
+    let mut cmd = cmd();
+    cmd.current_dir(dir.path());
+    (cmd, dir)
 }
 
 mod json {
@@ -14,6 +28,8 @@ mod json {
     const VALID_JSON: &str = r#"{"key": "value"}"#;
     const VALID_COMPLEX_JSON: &str =
         r#" { "foo": "bar", "baz": { "quz": "qork" }, "boo": [ "bah", { "lol": "lurg" } ] } "#;
+    const SERVERS_JSON: &str = r#"{ "servers": [ { "host": "alpha", "port": 80 }, { "host": "beta" } ], "db": { "host": "db", "replica": { "eu": { "host": "eu" } } } }"#;
+    const SCALARS_JSON: &str = r#"{ "port": 8080, "ratio": 0.30000000000000000004, "debug": true, "nothing": null, "servers": [-1, false] }"#;
 
     // Test that the CLI fails when the input file is a invalid json
     #[test]
@@ -23,9 +39,30 @@ mod json {
         file.write_str(INVALID_JSON).unwrap();
         cmd.arg(file.path());
         dbg!(cmd.output().unwrap());
-        cmd.assert().failure().stderr(predicate::str::contains(
-            "Error: Json(Error(\"expected value\", line: 1, column: 9))\n",
+        cmd.assert().code(4).stderr(predicate::str::contains(
+            "Error: Invalid json: expected value\n --> ",
         ));
+        cmd.assert().stderr(predicate::str::ends_with(
+            "invalid.json:1:9\n  |\n1 | {\"key\": value}\n  |         ^\n",
+        ));
+    }
+
+    #[test]
+    fn test_invalid_match_template() {
+        let mut cmd = cmd();
+        let file = assert_fs::NamedTempFile::new("valid.json").unwrap();
+        file.write_str(r#"{"a": 1, "b": 2}"#).unwrap();
+        cmd.arg(file.path())
+            .arg("--match")
+            .arg(r#"{"a": $A, "b" $B}"#);
+        cmd.assert().code(2).stderr(
+            r#"Error: Invalid json: expected `:`
+ --> --match:1:15
+  |
+1 | {"a": $A, "b" $B}
+  |               ^
+"#,
+        );
     }
 
     #[test]
@@ -37,7 +74,7 @@ mod json {
         dbg!(cmd.output().unwrap());
         cmd.assert()
             .success()
-            .stdout(predicate::str::contains("key=value; export key;"));
+            .stdout(predicate::str::contains("key='value'; export key;"));
     }
 
     #[test]
@@ -48,10 +85,10 @@ mod json {
         cmd.arg(file.path());
         dbg!(cmd.output().unwrap());
         cmd.assert().success().stdout(predicate::str::contains(
-            r#"baz.quz=qork; export baz.quz;
-boo.0=bah; export boo.0;
-boo.1.lol=lurg; export boo.1.lol;
-foo=bar; export foo;"#,
+            r#"baz_quz='qork'; export baz_quz;
+boo_0='bah'; export boo_0;
+boo_1_lol='lurg'; export boo_1_lol;
+foo='bar'; export foo;"#,
         ));
     }
 
@@ -65,12 +102,118 @@ foo=bar; export foo;"#,
             .arg(r#"{"foo": $BAR, "boo": [$BAH, { "lol": $LURG }] }"#);
         dbg!(cmd.output().unwrap());
         cmd.assert().success().stdout(predicate::str::contains(
-            r#"BAH=bah; export BAH;
-LURG=lurg; export LURG;
-BAR=bar; export BAR;"#,
+            r#"BAH='bah'; export BAH;
+LURG='lurg'; export LURG;
+BAR='bar'; export BAR;"#,
         ));
     }
 
+    #[test]
+    fn test_match_captures_subtree_as_json() {
+        let mut cmd = cmd();
+        let file = assert_fs::NamedTempFile::new("valid_complex.json").unwrap();
+        file.write_str(VALID_COMPLEX_JSON).unwrap();
+        cmd.arg(file.path())
+            .arg("--match")
+            .arg(r#"{"baz": $BAZ, "boo": $BOO}"#);
+        cmd.assert().success().stdout(
+            r#"BAZ='{"quz":"qork"}'; export BAZ;
+BOO='["bah",{"lol":"lurg"}]'; export BOO;
+"#,
+        );
+    }
+
+    #[test]
+    fn test_match_captures_subtree_flattened() {
+        let mut cmd = cmd();
+        let file = assert_fs::NamedTempFile::new("valid_complex.json").unwrap();
+        file.write_str(VALID_COMPLEX_JSON).unwrap();
+        cmd.arg(file.path())
+            .arg("--match")
+            .arg(r#"{"boo": $BOO}"#)
+            .arg("--subtrees")
+            .arg("flatten");
+        cmd.assert().success().stdout(
+            r#"BOO_0='bah'; export BOO_0;
+BOO_1_lol='lurg'; export BOO_1_lol;
+"#,
+        );
+    }
+
+    #[test]
+    fn test_match_wildcard_array() {
+        let mut cmd = cmd();
+        let file = assert_fs::NamedTempFile::new("servers.json").unwrap();
+        file.write_str(SERVERS_JSON).unwrap();
+        cmd.arg(file.path())
+            .arg("--match")
+            .arg(r#"{"servers": [{"host": $HOST_*}]}"#);
+        cmd.assert().success().stdout(
+            r#"HOST_0='alpha'; export HOST_0;
+HOST_1='beta'; export HOST_1;
+"#,
+        );
+    }
+
+    #[test]
+    fn test_match_wildcard_path_form() {
+        let mut cmd = cmd();
+        let file = assert_fs::NamedTempFile::new("servers.json").unwrap();
+        file.write_str(SERVERS_JSON).unwrap();
+        cmd.arg(file.path())
+            .arg("--match")
+            .arg("servers.*.host=$HOST_{}; servers.0.port=$PORT");
+        cmd.assert().success().stdout(
+            r#"HOST_0='alpha'; export HOST_0;
+HOST_1='beta'; export HOST_1;
+PORT='80'; export PORT;
+"#,
+        );
+    }
+
+    #[test]
+    fn test_match_any_depth_wildcard() {
+        let mut cmd = cmd();
+        let file = assert_fs::NamedTempFile::new("servers.json").unwrap();
+        file.write_str(SERVERS_JSON).unwrap();
+        cmd.arg(file.path())
+            .arg("--match")
+            .arg(r#"{"db": {"**": {"host": $DB_*_HOST}}}"#);
+        cmd.assert().success().stdout(
+            r#"DB__HOST='db'; export DB__HOST;
+DB_replica_eu_HOST='eu'; export DB_replica_eu_HOST;
+"#,
+        );
+    }
+
+    #[test]
+    fn test_match_defaults_missing_values() {
+        let mut cmd = cmd();
+        let file = assert_fs::NamedTempFile::new("servers.json").unwrap();
+        file.write_str(SERVERS_JSON).unwrap();
+        cmd.arg(file.path())
+            .arg("--match")
+            .arg(r#"{"db": {"host": $DB_HOST:-unused, "port": $DB_PORT:-5432}}"#);
+        cmd.assert().success().stdout(
+            r#"DB_HOST='db'; export DB_HOST;
+DB_PORT='5432'; export DB_PORT;
+"#,
+        );
+    }
+
+    #[test]
+    fn test_match_lists_every_missing_required_value() {
+        let mut cmd = cmd();
+        let file = assert_fs::NamedTempFile::new("servers.json").unwrap();
+        file.write_str(SERVERS_JSON).unwrap();
+        cmd.arg(file.path())
+            .arg("--match")
+            .arg(r#"{"db": {"host": $DB_HOST!, "user": $DB_USER!}, "api": {"key": $API_KEY!}}"#);
+        cmd.assert().code(7).stdout("").stderr(
+            "Error: Required values are missing: `API_KEY` at `api.key`, `DB_USER` at `db.user`\n",
+        );
+    }
+
     #[test]
     fn test_valid_complex_json_custom_separator() {
         let mut cmd = cmd();
@@ -79,10 +222,10 @@ BAR=bar; export BAR;"#,
         cmd.arg(file.path()).arg("--separator").arg("_");
         dbg!(cmd.output().unwrap());
         cmd.assert().success().stdout(predicate::str::contains(
-            r#"baz_quz=qork; export baz_quz;
-boo_0=bah; export boo_0;
-boo_1_lol=lurg; export boo_1_lol;
-foo=bar; export foo;"#,
+            r#"baz_quz='qork'; export baz_quz;
+boo_0='bah'; export boo_0;
+boo_1_lol='lurg'; export boo_1_lol;
+foo='bar'; export foo;"#,
         ));
     }
 
@@ -98,10 +241,1481 @@ foo=bar; export foo;"#,
             .arg("upper");
         dbg!(cmd.output().unwrap());
         cmd.assert().success().stdout(predicate::str::contains(
-            r#"BAZ_QUZ=qork; export BAZ_QUZ;
-BOO_0=bah; export BOO_0;
-BOO_1_LOL=lurg; export BOO_1_LOL;
-FOO=bar; export FOO;"#,
+            r#"BAZ_QUZ='qork'; export BAZ_QUZ;
+BOO_0='bah'; export BOO_0;
+BOO_1_LOL='lurg'; export BOO_1_LOL;
+FOO='bar'; export FOO;"#,
+        ));
+    }
+
+    #[test]
+    fn test_json_scalars() {
+        let mut cmd = cmd();
+        let file = assert_fs::NamedTempFile::new("scalars.json").unwrap();
+        file.write_str(SCALARS_JSON).unwrap();
+        cmd.arg(file.path());
+        dbg!(cmd.output().unwrap());
+        cmd.assert().success().stdout(predicate::eq(
+            r#"debug='true'; export debug;
+nothing=''; export nothing;
+port='8080'; export port;
+ratio='0.30000000000000000004'; export ratio;
+servers_0='-1'; export servers_0;
+servers_1='false'; export servers_1;
+"#,
+        ));
+    }
+
+    #[test]
+    fn test_json_null_policies() {
+        let mut cmd = cmd();
+        let file = assert_fs::NamedTempFile::new("scalars.json").unwrap();
+        file.write_str(SCALARS_JSON).unwrap();
+        cmd.arg(file.path()).arg("--null").arg("literal");
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("nothing='null'; export nothing;"));
+
+        let mut cmd = super::cmd();
+        cmd.arg(file.path()).arg("--null").arg("skip");
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("nothing").not());
+    }
+
+    #[test]
+    fn test_deeply_nested_json() {
+        let mut cmd = cmd();
+        let file = assert_fs::NamedTempFile::new("nested.json").unwrap();
+        file.write_str(r#"{"a": {"b": {"c": "x"}}, "d": {"b": {"c": "y"}}}"#)
+            .unwrap();
+        cmd.arg(file.path());
+        dbg!(cmd.output().unwrap());
+        cmd.assert().success().stdout(predicate::eq(
+            r#"a_b_c='x'; export a_b_c;
+d_b_c='y'; export d_b_c;
+"#,
+        ));
+    }
+
+    #[test]
+    fn test_json_key_collision() {
+        let mut cmd = cmd();
+        let file = assert_fs::NamedTempFile::new("collision.json").unwrap();
+        file.write_str(r#"{"A": "first", "a": {"b": "nested"}, "a_b": "flat"}"#)
+            .unwrap();
+        cmd.arg(file.path()).arg("--separator").arg("_");
+        dbg!(cmd.output().unwrap());
+        // Nothing half printed for `eval` to pick up
+        cmd.assert()
+            .code(5)
+            .stdout(predicate::str::is_empty())
+            .stderr(predicate::str::contains(
+                r#"Key `a_b` is produced by both ["a", "b"] and ["a_b"]"#,
+            ));
+
+        let mut cmd = super::cmd();
+        cmd.arg(file.path())
+            .arg("--separator")
+            .arg("_")
+            .arg("--collisions")
+            .arg("warn");
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("a_b='flat'; export a_b;"))
+            .stderr(predicate::str::contains(
+                "Warning: Key `a_b` is produced by both",
+            ));
+    }
+
+    #[test]
+    fn test_key_errors_name_the_keys_without_a_snippet() {
+        let mut cmd = cmd();
+        let file = assert_fs::NamedTempFile::new("keys.json").unwrap();
+        file.write_str("{\n  \"a\": {\"b\": 1},\n  \"a.b\": 2\n}")
+            .unwrap();
+        cmd.arg(file.path());
+        dbg!(cmd.output().unwrap());
+        cmd.assert().code(5).stderr(predicate::eq(
+            "Error: Key `a_b` is produced by both [\"a\", \"b\"] and [\"a.b\"]\n",
+        ));
+
+        let mut cmd = super::cmd();
+        cmd.arg(file.path()).arg("--match").arg(r#"{"c": $C!}"#);
+        cmd.assert().code(7).stderr(predicate::eq(
+            "Error: Required values are missing: `C` at `c`\n",
+        ));
+    }
+}
+
+mod yaml {
+    use super::*;
+
+    const VALID_COMPLEX_YAML: &str = r#"
+foo: bar
+baz:
+  quz: qork
+boo:
+  - bah
+  - lol: lurg
+"#;
+    const ANCHORS_YAML: &str = r#"
+base: &base
+  host: localhost
+  user: admin
+prod:
+  <<: *base
+  host: example.com
+mirror: *base
+"#;
+    const MULTI_DOCUMENT_YAML: &str = r#"
+name: first
+---
+name: second
+"#;
+
+    #[test]
+    fn test_valid_complex_yaml() {
+        let mut cmd = cmd();
+        let file = assert_fs::NamedTempFile::new("valid_complex.yaml").unwrap();
+        file.write_str(VALID_COMPLEX_YAML).unwrap();
+        cmd.arg(file.path());
+        dbg!(cmd.output().unwrap());
+        cmd.assert().success().stdout(predicate::str::contains(
+            r#"baz_quz='qork'; export baz_quz;
+boo_0='bah'; export boo_0;
+boo_1_lol='lurg'; export boo_1_lol;
+foo='bar'; export foo;"#,
+        ));
+    }
+
+    #[test]
+    fn test_skipped_positions() {
+        let mut cmd = cmd();
+        let file = assert_fs::NamedTempFile::new("list.yaml").unwrap();
+        file.write_str("servers: [alpha, beta, gamma, delta]\n")
+            .unwrap();
+        cmd.arg(file.path())
+            .arg("--match")
+            .arg("{ servers: [_, _, $THIRD, _] }");
+        dbg!(cmd.output().unwrap());
+        cmd.assert()
+            .success()
+            .stdout(predicate::eq("THIRD='gamma'; export THIRD;\n"));
+
+        let mut cmd = super::cmd();
+        let file = assert_fs::NamedTempFile::new("list.json").unwrap();
+        file.write_str(r#"{"servers": ["alpha", "beta", "gamma"]}"#)
+            .unwrap();
+        cmd.arg(file.path())
+            .arg("--match")
+            .arg(r#"{"servers": [_, $SECOND, "_"]}"#);
+        cmd.assert()
+            .success()
+            .stdout(predicate::eq("SECOND='beta'; export SECOND;\n"));
+    }
+
+    #[test]
+    fn test_yaml_anchors_and_merge_keys() {
+        let mut cmd = cmd();
+        let file = assert_fs::NamedTempFile::new("anchors.yml").unwrap();
+        file.write_str(ANCHORS_YAML).unwrap();
+        cmd.arg(file.path());
+        dbg!(cmd.output().unwrap());
+        cmd.assert().success().stdout(predicate::str::contains(
+            r#"base_host='localhost'; export base_host;
+base_user='admin'; export base_user;
+mirror_host='localhost'; export mirror_host;
+mirror_user='admin'; export mirror_user;
+prod_host='example.com'; export prod_host;
+prod_user='admin'; export prod_user;"#,
+        ));
+    }
+
+    #[test]
+    fn test_yaml_multi_document() {
+        let mut cmd = cmd();
+        let file = assert_fs::NamedTempFile::new("multi.yaml").unwrap();
+        file.write_str(MULTI_DOCUMENT_YAML).unwrap();
+        cmd.arg(file.path());
+        dbg!(cmd.output().unwrap());
+        cmd.assert().success().stdout(predicate::str::contains(
+            r#"_0_name='first'; export _0_name;
+_1_name='second'; export _1_name;"#,
+        ));
+    }
+
+    #[test]
+    fn test_valid_complex_yaml_with_matches() {
+        let mut cmd = cmd();
+        let file = assert_fs::NamedTempFile::new("valid_complex.yaml").unwrap();
+        file.write_str(VALID_COMPLEX_YAML).unwrap();
+        cmd.arg(file.path())
+            .arg("--match")
+            .arg("foo: $BAR\nboo: [$BAH, { lol: $LURG }]");
+        dbg!(cmd.output().unwrap());
+        cmd.assert().success().stdout(predicate::str::contains(
+            r#"BAH='bah'; export BAH;
+LURG='lurg'; export LURG;
+BAR='bar'; export BAR;"#,
+        ));
+    }
+
+    #[test]
+    fn test_yaml_from_stdin() {
+        let mut cmd = cmd();
+        cmd.write_stdin("foo: bar\n");
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("foo='bar'; export foo;"));
+    }
+}
+
+mod toml {
+    use super::*;
+
+    const VALID_COMPLEX_TOML: &str = r#"
+foo = "bar"
+boo = ["bah", { lol = "lurg" }]
+server.host = "localhost"
+
+[baz]
+quz = "qork"
+
+[[servers]]
+name = "alpha"
+
+[[servers]]
+name = "omega"
+"#;
+
+    #[test]
+    fn test_valid_complex_toml() {
+        let mut cmd = cmd();
+        let file = assert_fs::NamedTempFile::new("valid_complex.toml").unwrap();
+        file.write_str(VALID_COMPLEX_TOML).unwrap();
+        cmd.arg(file.path());
+        dbg!(cmd.output().unwrap());
+        cmd.assert().success().stdout(predicate::str::contains(
+            r#"baz_quz='qork'; export baz_quz;
+boo_0='bah'; export boo_0;
+boo_1_lol='lurg'; export boo_1_lol;
+foo='bar'; export foo;
+server_host='localhost'; export server_host;
+servers_0_name='alpha'; export servers_0_name;
+servers_1_name='omega'; export servers_1_name;"#,
+        ));
+    }
+
+    #[test]
+    fn test_invalid_toml() {
+        let mut cmd = cmd();
+        let file = assert_fs::NamedTempFile::new("invalid.toml").unwrap();
+        file.write_str("foo = \n").unwrap();
+        cmd.arg(file.path());
+        dbg!(cmd.output().unwrap());
+        cmd.assert()
+            .code(4)
+            .stderr(predicate::str::contains("Error: Invalid toml: "))
+            .stderr(predicate::str::contains("invalid.toml:1:7"));
+    }
+
+    #[test]
+    fn test_valid_complex_toml_with_matches() {
+        let mut cmd = cmd();
+        let file = assert_fs::NamedTempFile::new("valid_complex.toml").unwrap();
+        file.write_str(VALID_COMPLEX_TOML).unwrap();
+        cmd.arg(file.path()).arg("--match").arg(
+            r#"foo = $BAR
+boo = [$BAH, { lol = $LURG }]
+[[servers]]
+[[servers]]
+name = $LAST_SERVER"#,
+        );
+        dbg!(cmd.output().unwrap());
+        cmd.assert().success().stdout(predicate::str::contains(
+            r#"BAH='bah'; export BAH;
+LURG='lurg'; export LURG;
+BAR='bar'; export BAR;
+LAST_SERVER='omega'; export LAST_SERVER;"#,
+        ));
+    }
+}
+
+mod xml {
+    use super::*;
+
+    const VALID_COMPLEX_XML: &str = r#"<?xml version="1.0"?>
+<server port="8080">
+  <!-- comments are ignored -->
+  <host>localhost</host>
+  <name><![CDATA[Picnic & co]]></name>
+  first <b>bold</b> second
+</server>
+"#;
+
+    #[test]
+    fn test_valid_complex_xml() {
+        let mut cmd = cmd();
+        let file = assert_fs::NamedTempFile::new("valid_complex.xml").unwrap();
+        file.write_str(VALID_COMPLEX_XML).unwrap();
+        cmd.arg(file.path());
+        dbg!(cmd.output().unwrap());
+        cmd.assert().success().stdout(predicate::str::contains(
+            r#"server__text='first second'; export server__text;
+server__port='8080'; export server__port;
+server_b='bold'; export server_b;
+server_host='localhost'; export server_host;
+server_name='Picnic & co'; export server_name;"#,
+        ));
+    }
+
+    #[test]
+    fn test_xml_repeated_nested_elements() {
+        let mut cmd = cmd();
+        let file = assert_fs::NamedTempFile::new("repeated.xml").unwrap();
+        file.write_str(
+            r#"<config><paths><path>/srv</path><path>/opt</path></paths><db><main port="5432"/></db></config>"#,
+        )
+        .unwrap();
+        cmd.arg(file.path());
+        dbg!(cmd.output().unwrap());
+        cmd.assert().success().stdout(predicate::eq(
+            r#"config_db_main__port='5432'; export config_db_main__port;
+config_paths_path_0='/srv'; export config_paths_path_0;
+config_paths_path_1='/opt'; export config_paths_path_1;
+"#,
+        ));
+    }
+
+    #[test]
+    fn test_xml_custom_attribute_prefix() {
+        let mut cmd = cmd();
+        let file = assert_fs::NamedTempFile::new("valid_complex.xml").unwrap();
+        file.write_str(VALID_COMPLEX_XML).unwrap();
+        cmd.arg(file.path())
+            .arg("--attribute-prefix")
+            .arg("attr_")
+            .arg("--separator")
+            .arg("_");
+        dbg!(cmd.output().unwrap());
+        cmd.assert().success().stdout(predicate::str::contains(
+            "server_attr_port='8080'; export server_attr_port;",
+        ));
+    }
+
+    #[test]
+    fn test_valid_complex_xml_with_matches() {
+        let mut cmd = cmd();
+        let file = assert_fs::NamedTempFile::new("valid_complex.xml").unwrap();
+        file.write_str(VALID_COMPLEX_XML).unwrap();
+        cmd.arg(file.path())
+            .arg("--match")
+            .arg(r#"<server port="$PORT"><host>$HOST</host></server>"#);
+        dbg!(cmd.output().unwrap());
+        cmd.assert().success().stdout(predicate::str::contains(
+            r#"PORT='8080'; export PORT;
+HOST='localhost'; export HOST;"#,
+        ));
+    }
+}
+
+mod csv {
+    use super::*;
+
+    const VALID_CSV: &str =
+        "name,port,motd\nalpha,8080,\"hello, world\"\nomega,9090,\"say \"\"hi\"\"\"\n";
+    const VALID_TSV: &str = "name\tport\nalpha\t8080\n";
+
+    #[test]
+    fn test_valid_csv() {
+        let mut cmd = cmd();
+        let file = assert_fs::NamedTempFile::new("valid.csv").unwrap();
+        file.write_str(VALID_CSV).unwrap();
+        cmd.arg(file.path());
+        dbg!(cmd.output().unwrap());
+        cmd.assert().success().stdout(predicate::str::contains(
+            r#"_0_motd='hello, world'; export _0_motd;
+_0_name='alpha'; export _0_name;
+_0_port='8080'; export _0_port;
+_1_motd='say "hi"'; export _1_motd;
+_1_name='omega'; export _1_name;
+_1_port='9090'; export _1_port;"#,
+        ));
+    }
+
+    #[test]
+    fn test_valid_tsv() {
+        let mut cmd = cmd();
+        let file = assert_fs::NamedTempFile::new("valid.tsv").unwrap();
+        file.write_str(VALID_TSV).unwrap();
+        cmd.arg(file.path());
+        dbg!(cmd.output().unwrap());
+        cmd.assert().success().stdout(predicate::str::contains(
+            r#"_0_name='alpha'; export _0_name;
+_0_port='8080'; export _0_port;"#,
+        ));
+    }
+
+    #[test]
+    fn test_tsv_values_with_commas() {
+        let mut cmd = cmd();
+        let file = assert_fs::NamedTempFile::new("tags.tsv").unwrap();
+        file.write_str("name\ttags\nweb\ta,b,c\n").unwrap();
+        cmd.arg(file.path());
+        dbg!(cmd.output().unwrap());
+        cmd.assert().success().stdout(predicate::eq(
+            "_0_name='web'; export _0_name;\n_0_tags='a,b,c'; export _0_tags;\n",
         ));
     }
+
+    #[test]
+    fn test_csv_key_column() {
+        let mut cmd = cmd();
+        let file = assert_fs::NamedTempFile::new("valid.csv").unwrap();
+        file.write_str(VALID_CSV).unwrap();
+        cmd.arg(file.path())
+            .arg("--key-column")
+            .arg("name")
+            .arg("--match")
+            .arg("name,port\nomega,$OMEGA_PORT");
+        dbg!(cmd.output().unwrap());
+        cmd.assert()
+            .success()
+            .stdout(predicate::eq("OMEGA_PORT='9090'; export OMEGA_PORT;\n"));
+    }
+
+    #[test]
+    fn test_csv_unknown_key_column() {
+        let mut cmd = cmd();
+        let file = assert_fs::NamedTempFile::new("valid.csv").unwrap();
+        file.write_str(VALID_CSV).unwrap();
+        cmd.arg(file.path()).arg("--key-column").arg("nope");
+        dbg!(cmd.output().unwrap());
+        cmd.assert()
+            .failure()
+            .stderr(predicate::str::contains("Column `nope` not found"));
+    }
+}
+
+mod dotenv {
+    use super::*;
+
+    #[test]
+    fn test_invalid_dotenv_line() {
+        let mut cmd = cmd();
+        let file = assert_fs::NamedTempFile::new(".env").unwrap();
+        file.write_str("A=1\nFOO BAR\n").unwrap();
+        cmd.arg(file.path());
+        cmd.assert().code(4).stderr(predicate::str::ends_with(
+            ".env:2:5\n  |\n2 | FOO BAR\n  |     ^\n",
+        ));
+    }
+
+    // Quotes go on until the end of the file, point at where they open
+    #[test]
+    fn test_unterminated_quote() {
+        let mut cmd = cmd();
+        let file = assert_fs::NamedTempFile::new(".env").unwrap();
+        file.write_str("A='open\nB=2\n").unwrap();
+        cmd.arg(file.path());
+        cmd.assert()
+            .code(4)
+            .stderr(predicate::str::starts_with("Error: Invalid .env line\n"))
+            .stderr(predicate::str::contains(".env:1:3"));
+    }
+
+    #[test]
+    fn test_unterminated_quote_in_a_long_file() {
+        let mut cmd = cmd();
+        let file = assert_fs::NamedTempFile::new(".env").unwrap();
+        let lines = (0..20_000).map(|idx| format!("B{idx}=\"value {idx}\"\n"));
+        file.write_str(
+            &std::iter::once("A='open\n".to_owned())
+                .chain(lines)
+                .collect::<String>(),
+        )
+        .unwrap();
+        cmd.arg(file.path());
+        let started = std::time::Instant::now();
+        cmd.assert()
+            .code(4)
+            .stderr(predicate::str::contains(".env:1:3"));
+        // The open value is scanned once, not again for every line appended
+        assert!(started.elapsed() < std::time::Duration::from_secs(5));
+    }
+
+    const MULTILINE_ENV: &str = r#"KEY="-----BEGIN KEY-----
+abc # not a comment
+-----END KEY-----"
+JSON='{
+  "a": 1
+}'
+LONG=one\
+two\
+three
+AFTER=ok
+"#;
+
+    #[test]
+    fn test_multiline_values() {
+        let mut cmd = cmd();
+        let file = assert_fs::NamedTempFile::new(".env").unwrap();
+        file.write_str(MULTILINE_ENV).unwrap();
+        cmd.arg(file.path()).arg("--to").arg("json");
+        cmd.assert().success().stdout(
+            r#"{
+  "AFTER": "ok",
+  "JSON": "{\n  \"a\": 1\n}",
+  "KEY": "-----BEGIN KEY-----\nabc # not a comment\n-----END KEY-----",
+  "LONG": "onetwothree"
+}
+"#,
+        );
+    }
+
+    const EXEC_ENV: &str = r#"REV=$(echo abc)
+DATE="on `echo today`"
+KEPT='$(echo literal)'
+"#;
+
+    #[test]
+    fn test_command_substitution_warns_by_default() {
+        let mut cmd = cmd();
+        let file = assert_fs::NamedTempFile::new(".env").unwrap();
+        file.write_str(EXEC_ENV).unwrap();
+        cmd.arg(file.path());
+        cmd.assert()
+            .success()
+            .stdout(
+                r#"DATE='on `echo today`'; export DATE;
+KEPT='$(echo literal)'; export KEPT;
+REV='$(echo abc)'; export REV;
+"#,
+            )
+            .stderr(
+                r#"Warning: $(echo abc) at line 1, column 5 is kept as is, pass --allow-exec to run it
+Warning: `echo today` at line 2, column 6 is kept as is, pass --allow-exec to run it
+"#,
+            );
+    }
+
+    #[test]
+    fn test_command_substitution_allowed() {
+        let mut cmd = cmd();
+        let file = assert_fs::NamedTempFile::new(".env").unwrap();
+        file.write_str(EXEC_ENV).unwrap();
+        cmd.arg(file.path()).arg("--allow-exec");
+        cmd.assert().success().stderr("").stdout(
+            r#"DATE='on today'; export DATE;
+KEPT='$(echo literal)'; export KEPT;
+REV='abc'; export REV;
+"#,
+        );
+    }
+
+    #[test]
+    fn test_command_substitution_timeout() {
+        let mut cmd = cmd();
+        let file = assert_fs::NamedTempFile::new(".env").unwrap();
+        file.write_str("A=1\nB=$(sleep 5; echo late)\n").unwrap();
+        cmd.arg(file.path())
+            .arg("--allow-exec")
+            .arg("--exec-timeout")
+            .arg("0.2");
+        let started = std::time::Instant::now();
+        cmd.assert()
+            .code(8)
+            .stderr(predicate::str::starts_with(
+                "Error: `sleep 5; echo late` timed out after 0.2s\n",
+            ))
+            .stderr(predicate::str::contains(".env:2:3"));
+        // `sleep` is killed along with `sh`, instead of holding the output open
+        assert!(started.elapsed() < std::time::Duration::from_secs(3));
+    }
+
+    #[test]
+    fn test_command_substitution_timeout_covers_background_jobs() {
+        let mut cmd = cmd();
+        let file = assert_fs::NamedTempFile::new(".env").unwrap();
+        file.write_str("A=$(sleep 5 & echo hi)\n").unwrap();
+        cmd.arg(file.path())
+            .arg("--allow-exec")
+            .arg("--exec-timeout")
+            .arg("0.2");
+        let started = std::time::Instant::now();
+        // `sh` is done, but the backgrounded `sleep` still holds its stdout
+        cmd.assert().code(8).stderr(predicate::str::starts_with(
+            "Error: `sleep 5 & echo hi` timed out after 0.2s\n",
+        ));
+        assert!(started.elapsed() < std::time::Duration::from_secs(3));
+    }
+
+    #[test]
+    fn test_multiline_values_keep_their_line() {
+        let mut cmd = cmd();
+        let file = assert_fs::NamedTempFile::new(".env").unwrap();
+        file.write_str(MULTILINE_ENV).unwrap();
+        cmd.arg(file.path()).arg("--explain").arg("AFTER");
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::ends_with(".env:10:7\n"));
+    }
+
+    const EXPANSIONS_ENV: &str = r#"HOST=db
+EMPTY=
+DEFAULT=${NOPE:-fallback}
+EMPTY_DEFAULT=${EMPTY:-d1}
+UNSET_DEFAULT=${EMPTY-d2}
+ALT=${HOST:+alt}
+NO_ALT=${NOPE:+alt}
+NESTED=${NOPE:-${ALSO:-${HOST}_2}}
+URL="http://$HOST_NAME/${HOST}"
+"#;
+
+    #[test]
+    fn test_parameter_expansion() {
+        let mut cmd = cmd();
+        let file = assert_fs::NamedTempFile::new(".env").unwrap();
+        file.write_str(EXPANSIONS_ENV).unwrap();
+        // Only the file's own lines, whatever the developer has exported
+        cmd.arg(file.path()).arg("--no-env");
+        cmd.assert().success().stdout(
+            r#"ALT='alt'; export ALT;
+DEFAULT='fallback'; export DEFAULT;
+EMPTY=''; export EMPTY;
+EMPTY_DEFAULT='d1'; export EMPTY_DEFAULT;
+HOST='db'; export HOST;
+NESTED='db_2'; export NESTED;
+NO_ALT=''; export NO_ALT;
+UNSET_DEFAULT=''; export UNSET_DEFAULT;
+URL='http:///db'; export URL;
+"#,
+        );
+    }
+
+    #[test]
+    fn test_unset_vars_kept_literally() {
+        let mut cmd = cmd();
+        let file = assert_fs::NamedTempFile::new(".env").unwrap();
+        file.write_str("URL=\"http://$HOST_NAME/${PORT}\"\n")
+            .unwrap();
+        cmd.arg(file.path())
+            .arg("--unset-vars")
+            .arg("keep")
+            .env_remove("HOST_NAME")
+            .env_remove("PORT");
+        cmd.assert()
+            .success()
+            .stdout("URL='http://$HOST_NAME/${PORT}'; export URL;\n");
+    }
+
+    #[test]
+    fn test_required_expansion() {
+        let mut cmd = cmd();
+        let file = assert_fs::NamedTempFile::new(".env").unwrap();
+        file.write_str("A=1\nB=${DB_URL:?database url is required}\n")
+            .unwrap();
+        cmd.arg(file.path()).env_remove("DB_URL");
+        cmd.assert().code(7).stderr(predicate::str::starts_with(
+            "Error: `DB_URL` is not set: database url is required\n",
+        ));
+    }
+
+    const SHADOWED_ENV: [(&str, &str); 2] = [
+        (".env", "FOO=file\nA=$FOO\nB=$HOME\n"),
+        ("ci.env", "HOME=/ci\nFOO=ci\n"),
+    ];
+    const SHADOWED_ARGS: [&str; 3] = [".env", "--match", "A=$A;B=$B"];
+
+    #[test]
+    fn test_process_env_wins_by_default() {
+        let (mut cmd, _dir) = cmd_with_files(&SHADOWED_ENV);
+        cmd.env("FOO", "shell")
+            .env("HOME", "/home/me")
+            .args(SHADOWED_ARGS);
+        cmd.assert().success().stdout(
+            r#"A='shell'; export A;
+B='/home/me'; export B;
+"#,
+        );
+    }
+
+    #[test]
+    fn test_file_env_precedence() {
+        let (mut cmd, _dir) = cmd_with_files(&SHADOWED_ENV);
+        cmd.env("FOO", "shell")
+            .env("HOME", "/home/me")
+            .args(SHADOWED_ARGS)
+            .args(["--env-precedence", "file"]);
+        cmd.assert().success().stdout(
+            r#"A='file'; export A;
+B='/home/me'; export B;
+"#,
+        );
+    }
+
+    #[test]
+    fn test_no_env() {
+        let (mut cmd, _dir) = cmd_with_files(&SHADOWED_ENV);
+        cmd.env("FOO", "shell")
+            .env("HOME", "/home/me")
+            .args(SHADOWED_ARGS)
+            .arg("--no-env");
+        cmd.assert().success().stdout(
+            r#"A='file'; export A;
+B=''; export B;
+"#,
+        );
+    }
+
+    #[test]
+    fn test_env_file() {
+        let (mut cmd, _dir) = cmd_with_files(&SHADOWED_ENV);
+        cmd.env("FOO", "shell")
+            .env("HOME", "/home/me")
+            .args(SHADOWED_ARGS)
+            .args(["--env-file", "ci.env"]);
+        cmd.assert().success().stdout(
+            r#"A='ci'; export A;
+B='/ci'; export B;
+"#,
+        );
+        let (mut cmd, _dir) = cmd_with_files(&SHADOWED_ENV);
+        cmd.args(SHADOWED_ARGS)
+            .args(["--env-file", "ci.env", "--no-env"]);
+        cmd.assert()
+            .code(2)
+            .stderr(predicate::str::contains("cannot be used with"));
+    }
+}
+
+mod query {
+    use super::*;
+
+    const SERVICES_JSON: &str = r#"{ "services": [ { "name": "web", "enabled": true, "port": 80 }, { "name": "db", "enabled": false, "port": 5432 }, { "name": "cache", "port": 6379 } ], "db": { "host": "localhost" } }"#;
+
+    #[test]
+    fn test_filter_on_truthy_field() {
+        let (mut cmd, _dir) = cmd_with_files(&[("services.json", SERVICES_JSON)]);
+        cmd.args(["services.json", "--query", "$.services[?(@.enabled)].name"]);
+        cmd.assert()
+            .success()
+            .stdout("services_0_name='web'; export services_0_name;\n");
+    }
+
+    #[test]
+    fn test_filter_with_comparisons() {
+        let (mut cmd, _dir) = cmd_with_files(&[("services.json", SERVICES_JSON)]);
+        cmd.args([
+            "services.json",
+            "--query",
+            r#"$.services[?(@.port > 100 && @.name != "db")].name"#,
+        ]);
+        cmd.assert()
+            .success()
+            .stdout("services_2_name='cache'; export services_2_name;\n");
+    }
+
+    #[test]
+    fn test_recursive_descent_and_indexes() {
+        let (mut cmd, _dir) = cmd_with_files(&[("services.json", SERVICES_JSON)]);
+        cmd.args(["services.json", "--query", "$..host"]);
+        cmd.assert()
+            .success()
+            .stdout("db_host='localhost'; export db_host;\n");
+        let (mut cmd, _dir) = cmd_with_files(&[("services.json", SERVICES_JSON)]);
+        cmd.args(["services.json", "--query", "$.services[-1, 0]['port']"]);
+        cmd.assert().success().stdout(
+            r#"services_0_port='80'; export services_0_port;
+services_2_port='6379'; export services_2_port;
+"#,
+        );
+    }
+
+    // Untyped formats compare numbers all the same
+    #[test]
+    fn test_query_csv() {
+        let (mut cmd, _dir) = cmd_with_files(&[("services.csv", "name,port\nweb,80\ndb,5432\n")]);
+        cmd.args(["services.csv", "--query", "$[?(@.port < 1000)].name"]);
+        cmd.assert()
+            .success()
+            .stdout("_0_name='web'; export _0_name;\n");
+    }
+
+    #[test]
+    fn test_invalid_query() {
+        let (mut cmd, _dir) = cmd_with_files(&[("services.json", SERVICES_JSON)]);
+        cmd.args(["services.json", "--query", "$.services[?(@.port >)]"]);
+        cmd.assert().code(2).stderr(predicate::str::contains(
+            r#"Error: Invalid query, expected `@`, a string, a number, `true`, `false` or `null`
+ --> --query:1:22
+  |
+1 | $.services[?(@.port >)]
+  |                      ^"#,
+        ));
+    }
+}
+
+mod merge {
+    use super::*;
+
+    const BASE_JSON: &str =
+        r#"{"db": {"host": "localhost", "port": 5432}, "servers": ["a", "b"], "debug": true}"#;
+    const PROD_YAML: &str = "db:\n  host: prod-db\nservers:\n  - c\n";
+    const LOCAL_ENV: &str = "debug=false\ndb.port=6543\n";
+    const LAYERS: [(&str, &str); 3] = [
+        ("base.json", BASE_JSON),
+        ("prod.yaml", PROD_YAML),
+        (".env.local", LOCAL_ENV),
+    ];
+
+    #[test]
+    fn test_later_files_win() {
+        let (mut cmd, _dir) = cmd_with_files(&LAYERS);
+        cmd.args(["base.json", "prod.yaml", ".env.local"]);
+        cmd.assert().success().stdout(
+            r#"db_host='prod-db'; export db_host;
+db_port='6543'; export db_port;
+debug='false'; export debug;
+servers_0='c'; export servers_0;
+"#,
+        );
+        let (mut cmd, _dir) = cmd_with_files(&LAYERS);
+        cmd.args([".env.local", "base.json"]);
+        cmd.assert().success().stdout(predicate::str::contains(
+            "db_port='5432'; export db_port;\ndebug='true'; export debug;\n",
+        ));
+    }
+
+    #[test]
+    fn test_dotenv_templates_match_merged_layers() {
+        let (mut cmd, _dir) = cmd_with_files(&LAYERS);
+        cmd.args([".env.local", "base.json", "--match", "db.port=$P"]);
+        cmd.assert().success().stdout("P='5432'; export P;\n");
+
+        let (mut cmd, _dir) = cmd_with_files(&[("a.env", "DB_PORT=1\n"), ("b.env", "DB_HOST=x\n")]);
+        cmd.args(["a.env", "b.env", "-s", "_", "--match", "DB_PORT=$X"]);
+        cmd.assert().success().stdout("X='1'; export X;\n");
+    }
+
+    #[test]
+    fn test_nesting_never_drops_values() {
+        let (mut cmd, _dir) = cmd_with_files(&[("a.env", "A=1\nA_B=2\n"), ("b.env", "C=3\n")]);
+        cmd.args(["a.env", "b.env", "-s", "_"]);
+        cmd.assert()
+            .code(5)
+            .stdout("")
+            .stderr("Error: Key `A_B` can't be nested on `_`, `A` is already set\n");
+    }
+
+    #[test]
+    fn test_array_strategies() {
+        let (mut cmd, _dir) = cmd_with_files(&LAYERS);
+        cmd.args(["base.json", "prod.yaml", "--arrays", "append"]);
+        cmd.assert().success().stdout(predicate::str::contains(
+            r#"servers_0='a'; export servers_0;
+servers_1='b'; export servers_1;
+servers_2='c'; export servers_2;
+"#,
+        ));
+        let (mut cmd, _dir) = cmd_with_files(&LAYERS);
+        cmd.args(["base.json", "prod.yaml", "--arrays", "merge-by-index"]);
+        cmd.assert().success().stdout(predicate::str::contains(
+            r#"servers_0='c'; export servers_0;
+servers_1='b'; export servers_1;
+"#,
+        ));
+    }
+
+    #[test]
+    fn test_explain() {
+        let (mut cmd, _dir) = cmd_with_files(&LAYERS);
+        cmd.args([
+            "base.json",
+            "prod.yaml",
+            ".env.local",
+            "--explain",
+            "db_port",
+        ]);
+        cmd.assert()
+            .success()
+            .stdout("db_port='6543' from .env.local:2:9\n");
+        let (mut cmd, _dir) = cmd_with_files(&LAYERS);
+        cmd.args(["base.json", "prod.yaml", "--explain", "servers.0"]);
+        cmd.assert()
+            .success()
+            .stdout("servers_0='c' from prod.yaml\n");
+        let (mut cmd, _dir) = cmd_with_files(&LAYERS);
+        cmd.args(["base.json", "prod.yaml", "--explain", "nope"]);
+        cmd.assert()
+            .code(5)
+            .stderr("Error: `nope` is not set by any input\n");
+    }
+}
+
+mod detect {
+    use super::*;
+
+    #[test]
+    fn test_json_without_extension() {
+        let (mut cmd, _dir) = cmd_with_files(&[("config", r#"{ "server": { "port": 80 } }"#)]);
+        cmd.arg("config");
+        cmd.assert().success().stdout(predicate::str::contains(
+            "server_port='80'; export server_port;",
+        ));
+    }
+
+    #[test]
+    fn test_toml_with_unknown_extension() {
+        let (mut cmd, _dir) = cmd_with_files(&[("settings.conf", "[server]\nport = 80\n")]);
+        cmd.arg("settings.conf");
+        cmd.assert().success().stdout(predicate::str::contains(
+            "server_port='80'; export server_port;",
+        ));
+    }
+
+    #[test]
+    fn test_dotenv_with_unknown_extension() {
+        let (mut cmd, _dir) = cmd_with_files(&[(
+            "settings.conf",
+            "# comment\nHOST=localhost\nexport PORT=80\n",
+        )]);
+        cmd.arg("settings.conf");
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("HOST='localhost'; export HOST;"))
+            .stdout(predicate::str::contains("PORT='80'; export PORT;"));
+    }
+
+    #[test]
+    fn test_guesses_never_run_commands() {
+        let dir = assert_fs::TempDir::new().unwrap();
+        let marker = dir.child("ran");
+        for input in [
+            format!("KEY = \"$(touch {})\"\n", marker.path().display()),
+            format!("[db]\nKEY = \"$(touch {})\"\n", marker.path().display()),
+        ] {
+            let mut cmd = cmd();
+            cmd.arg("--allow-exec").write_stdin(input);
+            cmd.assert().success();
+            marker.assert(predicate::path::missing());
+        }
+
+        let mut cmd = cmd();
+        cmd.arg("--allow-exec").write_stdin("KEY=$(echo ran)\n");
+        cmd.assert().success().stdout("KEY='ran'; export KEY;\n");
+    }
+
+    #[test]
+    fn test_csv_from_stdin() {
+        let mut cmd = cmd();
+        cmd.write_stdin("name,port\nweb,80\n");
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("_0_port='80'; export _0_port;"));
+    }
+
+    #[test]
+    fn test_format_override() {
+        let file = assert_fs::NamedTempFile::new("hosts.txt").unwrap();
+        file.write_str("- alpha\n- beta\n").unwrap();
+        let mut cmd = cmd();
+        cmd.arg(file.path()).args(["--format", "yaml"]);
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("_1='beta'; export _1;"));
+    }
+
+    #[test]
+    fn test_dotenv_from_stdin() {
+        let mut cmd = cmd();
+        cmd.write_stdin("HOST=localhost\nPORT=8080\n");
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("HOST='localhost'; export HOST;"))
+            .stdout(predicate::str::contains("PORT='8080'; export PORT;"));
+    }
+
+    #[test]
+    fn test_falls_back_when_the_likeliest_format_does_not_parse() {
+        let mut cmd = cmd();
+        // Looks like json at first sight, but only parses as yaml
+        cmd.write_stdin("{ foo: bar }");
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("foo='bar'; export foo;"));
+    }
+
+    #[test]
+    fn test_ambiguous_input() {
+        let mut cmd = cmd();
+        // toml keeps `$PICNIC_DETECT` as is, .env substitutes it
+        cmd.env("PICNIC_DETECT", "value")
+            .write_stdin("KEY = \"$PICNIC_DETECT\"\n");
+        cmd.assert().failure().stderr(predicate::str::contains(
+            "Input reads as both toml and dotenv, set it with --format",
+        ));
+    }
+
+    #[test]
+    fn test_undetectable_input() {
+        let (mut cmd, _dir) = cmd_with_files(&[("notes", "just some words\n")]);
+        cmd.arg("notes");
+        cmd.assert()
+            .failure()
+            .stderr(predicate::str::contains("Couldn't detect the input format"));
+    }
+}
+
+mod shell {
+    use super::*;
+
+    const NASTY_JSON: &str = r#"{"nasty": "it's $(echo pwned) `id`; a  b\n\\* \"done\""}"#;
+
+    #[test]
+    fn test_values_are_single_quoted() {
+        let mut cmd = cmd();
+        let file = assert_fs::NamedTempFile::new("nasty.json").unwrap();
+        file.write_str(NASTY_JSON).unwrap();
+        cmd.arg(file.path());
+        dbg!(cmd.output().unwrap());
+        cmd.assert().success().stdout(predicate::eq(
+            "nasty='it'\\''s $(echo pwned) `id`; a  b\n\\* \"done\"'; export nasty;\n",
+        ));
+    }
+
+    #[test]
+    fn test_eval_roundtrip() {
+        let file = assert_fs::NamedTempFile::new("nasty.json").unwrap();
+        file.write_str(NASTY_JSON).unwrap();
+        let bin = assert_cmd::cargo::cargo_bin("picnic");
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c")
+            .arg(r#"eval "$("$0" "$1")" && printf '%s' "$nasty""#)
+            .arg(bin)
+            .arg(file.path());
+        cmd.assert()
+            .success()
+            .stdout(predicate::eq("it's $(echo pwned) `id`; a  b\n\\* \"done\""));
+    }
+
+    #[test]
+    fn test_reject_invalid_keys() {
+        let mut cmd = cmd();
+        let file = assert_fs::NamedTempFile::new("nested.json").unwrap();
+        file.write_str(r#"{"baz": {"quz": "qork"}}"#).unwrap();
+        cmd.arg(file.path()).arg("--invalid-keys").arg("reject");
+        dbg!(cmd.output().unwrap());
+        cmd.assert().failure().stderr(predicate::str::contains(
+            "Error: `baz.quz` is not a valid variable name",
+        ));
+    }
+
+    #[test]
+    fn test_shell_dialects() {
+        let file = assert_fs::NamedTempFile::new("quotes.json").unwrap();
+        file.write_str(r#"{"msg": "it's a \\ \"test\"\n"}"#)
+            .unwrap();
+        for (shell, expected) in [
+            ("bash", "msg='it'\\''s a \\ \"test\"\n'; export msg;\n"),
+            ("fish", "set -gx msg 'it\\'s a \\\\ \"test\"\n';\n"),
+            ("powershell", "$env:msg = 'it''s a \\ \"test\"\n'\n"),
+            ("nushell", "$env.msg = \"it's a \\\\ \\\"test\\\"\\n\"\n"),
+        ] {
+            let mut cmd = cmd();
+            cmd.arg(file.path()).arg("--shell").arg(shell);
+            cmd.assert().success().stdout(predicate::eq(expected));
+        }
+
+        let file = assert_fs::NamedTempFile::new("typographic.json").unwrap();
+        file.write_str("{\"v\": \"a\u{2019}; Remove-Item x; \u{2018}\u{201a}\u{201b}b\"}")
+            .unwrap();
+        let mut cmd = cmd();
+        cmd.arg(file.path()).arg("--shell").arg("powershell");
+        cmd.assert().success().stdout(predicate::eq(
+            "$env:v = 'a\u{2019}\u{2019}; Remove-Item x; \u{2018}\u{2018}\u{201a}\u{201a}\u{201b}\u{201b}b'\n",
+        ));
+    }
+
+    #[test]
+    fn test_shell_detected_from_env() {
+        let mut cmd = cmd();
+        let file = assert_fs::NamedTempFile::new("valid.json").unwrap();
+        file.write_str(r#"{"key": "value"}"#).unwrap();
+        cmd.env("SHELL", "/usr/bin/fish").arg(file.path());
+        cmd.assert()
+            .success()
+            .stdout(predicate::eq("set -gx key 'value';\n"));
+    }
+}
+
+mod convert {
+    use super::*;
+
+    const VALID_COMPLEX_JSON: &str =
+        r#" { "foo": "bar", "baz": { "quz": "qork" }, "boo": [ "bah", { "lol": 1 } ] } "#;
+
+    #[test]
+    fn test_dotenv_to_nested_json() {
+        let mut cmd = cmd();
+        let file = assert_fs::NamedTempFile::new(".env").unwrap();
+        file.write_str("DB.HOST=localhost\nDB.PORTS.0=5432\nDB.PORTS.1=5433\n")
+            .unwrap();
+        cmd.arg(file.path()).arg("--to").arg("json");
+        dbg!(cmd.output().unwrap());
+        cmd.assert().success().stdout(predicate::eq(
+            r#"{
+  "DB": {
+    "HOST": "localhost",
+    "PORTS": [
+      "5432",
+      "5433"
+    ]
+  }
+}
+"#,
+        ));
+    }
+
+    #[test]
+    fn test_json_to_yaml() {
+        let mut cmd = cmd();
+        let file = assert_fs::NamedTempFile::new("valid_complex.json").unwrap();
+        file.write_str(VALID_COMPLEX_JSON).unwrap();
+        cmd.arg(file.path()).arg("--to").arg("yaml");
+        dbg!(cmd.output().unwrap());
+        cmd.assert().success().stdout(predicate::eq(
+            r#"baz:
+  quz: qork
+boo:
+- bah
+- lol: 1
+foo: bar
+"#,
+        ));
+    }
+
+    #[test]
+    fn test_json_matches_to_toml() {
+        let mut cmd = cmd();
+        let file = assert_fs::NamedTempFile::new("valid_complex.json").unwrap();
+        file.write_str(VALID_COMPLEX_JSON).unwrap();
+        cmd.arg(file.path())
+            .arg("--match")
+            .arg(r#"{"foo": $BAR, "boo": [$BAH, { "lol": $LURG }] }"#)
+            .arg("--to")
+            .arg("toml");
+        dbg!(cmd.output().unwrap());
+        cmd.assert().success().stdout(predicate::eq(
+            r#"BAH = "bah"
+BAR = "bar"
+LURG = 1
+"#,
+        ));
+    }
+
+    #[test]
+    fn test_json_to_dotenv() {
+        let mut cmd = cmd();
+        let file = assert_fs::NamedTempFile::new("quotes.json").unwrap();
+        file.write_str(r#"{"a": {"b": "it's $HOME"}, "c": "plain"}"#)
+            .unwrap();
+        cmd.arg(file.path()).arg("--to").arg("dotenv");
+        dbg!(cmd.output().unwrap());
+        cmd.assert().success().stdout(predicate::eq(
+            r#"a_b="it's \$HOME"
+c='plain'
+"#,
+        ));
+    }
+
+    #[test]
+    fn test_conflicting_keys_cant_be_nested() {
+        let mut cmd = cmd();
+        let file = assert_fs::NamedTempFile::new(".env").unwrap();
+        file.write_str("A=1\nA.B=2\n").unwrap();
+        cmd.arg(file.path()).arg("--to").arg("json");
+        dbg!(cmd.output().unwrap());
+        cmd.assert()
+            .failure()
+            .stderr(predicate::str::contains("Key `A.B` can't be nested"));
+    }
+
+    #[test]
+    fn test_rows_to_toml_is_a_conversion_error() {
+        let mut cmd = cmd();
+        let file = assert_fs::NamedTempFile::new("rows.csv").unwrap();
+        file.write_str("name,port\nweb,80\n").unwrap();
+        cmd.arg(file.path()).arg("--to").arg("toml");
+        dbg!(cmd.output().unwrap());
+        cmd.assert().code(6).stderr(predicate::eq(
+            "Error: Can't convert to toml, only tables can be\n",
+        ));
+    }
+
+    #[test]
+    fn test_toml_conversion_never_drops_values() {
+        let file = assert_fs::NamedTempFile::new("lossy.json").unwrap();
+
+        file.write_str(r#"{"big": 18446744073709551615}"#).unwrap();
+        let mut cmd = cmd();
+        cmd.arg(file.path()).arg("--to").arg("toml");
+        dbg!(cmd.output().unwrap());
+        cmd.assert().code(6).stdout("").stderr(predicate::eq(
+            "Error: Can't convert 18446744073709551615 to toml, integers must fit in 64 signed bits\n",
+        ));
+
+        // A left out array element would shift the ones after it
+        for json in [r#"{"list": [null, "b"]}"#, r#"{"a": null}"#] {
+            file.write_str(json).unwrap();
+            let mut cmd = super::cmd();
+            cmd.arg(file.path())
+                .arg("--to")
+                .arg("toml")
+                .arg("--null")
+                .arg("literal");
+            cmd.assert().code(6).stdout("").stderr(predicate::eq(
+                "Error: Can't convert null to toml, pass --null empty or --null skip\n",
+            ));
+        }
+    }
+}
+
+mod spawn {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    const VALID_COMPLEX_JSON: &str =
+        r#" { "foo": "bar", "baz": { "quz": "qork" }, "boo": [ "bah", { "lol": "lurg" } ] } "#;
+
+    #[test]
+    fn test_spawn_one_executable_per_key() {
+        let mut cmd = cmd();
+        let file = assert_fs::NamedTempFile::new("valid_complex.json").unwrap();
+        file.write_str(VALID_COMPLEX_JSON).unwrap();
+        let dir = assert_fs::TempDir::new().unwrap();
+        let target = dir.child("bin");
+        cmd.arg(file.path()).arg("--spawn").arg(target.path());
+        dbg!(cmd.output().unwrap());
+        cmd.assert()
+            .success()
+            .stderr(predicate::str::contains(format!(
+                "Spawned {}",
+                target.child("baz.quz").path().display()
+            )));
+        for key in ["foo", "baz.quz", "boo.0", "boo.1.lol"] {
+            let bin = target.child(key);
+            bin.assert(predicate::path::is_file());
+            let mode = std::fs::metadata(bin.path()).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o755, "{key} is not executable");
+        }
+    }
+
+    #[test]
+    fn test_spawn_refuses_path_traversal() {
+        let mut cmd = cmd();
+        let file = assert_fs::NamedTempFile::new("traversal.json").unwrap();
+        file.write_str(r#"{"..": {"evil": "x"}}"#).unwrap();
+        let dir = assert_fs::TempDir::new().unwrap();
+        cmd.arg(file.path())
+            .arg("--separator")
+            .arg("/")
+            .arg("--spawn")
+            .arg(dir.path());
+        dbg!(cmd.output().unwrap());
+        cmd.assert().failure().stderr(predicate::str::contains(
+            "Error: `../evil` can't be spawned, it is not a plain file name",
+        ));
+        dir.child("evil").assert(predicate::path::missing());
+    }
+
+    #[test]
+    fn test_spawn_replaces_symlinks_instead_of_following_them() {
+        let mut cmd = cmd();
+        let file = assert_fs::NamedTempFile::new("link.json").unwrap();
+        file.write_str(r#"{"foo": "bar"}"#).unwrap();
+        let dir = assert_fs::TempDir::new().unwrap();
+        let victim = dir.child("victim");
+        victim.write_str("untouched").unwrap();
+        let bin = dir.child("bin");
+        std::fs::create_dir(bin.path()).unwrap();
+        std::os::unix::fs::symlink(victim.path(), bin.child("foo").path()).unwrap();
+        cmd.arg(file.path()).arg("--spawn").arg(bin.path());
+        dbg!(cmd.output().unwrap());
+        cmd.assert().success();
+        victim.assert("untouched");
+        let meta = std::fs::symlink_metadata(bin.child("foo").path()).unwrap();
+        assert!(meta.is_file());
+        assert_eq!(meta.permissions().mode() & 0o777, 0o755);
+    }
+
+    const NASTY_VALUE: &str = "-n *  $(echo pwned) `id` \\c 'quoted' \"double\"\n";
+
+    fn spawn_and_run(extra_args: &[&str]) {
+        let file = assert_fs::NamedTempFile::new("nasty.json").unwrap();
+        file.write_str(&serde_json::json!({ "nasty": NASTY_VALUE }).to_string())
+            .unwrap();
+        let dir = assert_fs::TempDir::new().unwrap();
+        let mut cmd = cmd();
+        cmd.arg(file.path())
+            .arg("--spawn")
+            .arg(dir.path())
+            .args(extra_args);
+        cmd.assert().success();
+
+        let output = std::process::Command::new(dir.child("nasty").path())
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), NASTY_VALUE);
+    }
+
+    #[test]
+    fn test_spawned_script_prints_exact_value() {
+        spawn_and_run(&[]);
+    }
+
+    #[test]
+    #[cfg(all(
+        target_os = "linux",
+        any(target_arch = "x86_64", target_arch = "aarch64")
+    ))]
+    fn test_spawned_native_binary_prints_exact_value() {
+        spawn_and_run(&["--native"]);
+    }
+}
+
+mod lib {
+    use picnic::output::Collisions;
+    use picnic::{
+        Document, FlattenOptions, Format, Output, OutputOptions, ParseOptions, Scalar, Span,
+    };
+
+    #[test]
+    fn test_library_flatten_match_and_print() {
+        let entries = Document::from_str(
+            r#"{ "foo": "bar", "baz": { "quz": 1 } }"#,
+            Format::Json,
+            ParseOptions::default(),
+        )
+        .unwrap()
+        .flatten(FlattenOptions::default())
+        .matching(r#"{ "baz": { "quz": $QUZ } }"#)
+        .unwrap();
+
+        let mut output = Output::new(OutputOptions::default(), Vec::new());
+        for entry in &entries {
+            output.print(entry).unwrap();
+        }
+        output.finish().unwrap();
+        let printed = String::from_utf8(output.into_inner()).unwrap();
+        assert_eq!(printed, "QUZ='1'; export QUZ;\n");
+    }
+
+    #[test]
+    fn test_library_returns_warnings() {
+        let document = Document::from_str(
+            "REV=$(git rev-parse HEAD)\n",
+            Format::Dotenv,
+            ParseOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            document.warnings(),
+            ["$(git rev-parse HEAD) at line 1, column 5 is kept as is, pass --allow-exec to run it"]
+        );
+
+        let entries = Document::from_str(
+            r#"{ "a": { "b": 1 }, "a.b": 2 }"#,
+            Format::Json,
+            ParseOptions::default(),
+        )
+        .unwrap()
+        .flatten(FlattenOptions::default());
+        let mut output = Output::new(
+            OutputOptions {
+                collisions: Collisions::Warn,
+                ..OutputOptions::default()
+            },
+            Vec::new(),
+        );
+        for entry in &entries {
+            output.print(entry).unwrap();
+        }
+        assert_eq!(
+            output.warnings(),
+            [r#"Key `a_b` is produced by both ["a", "b"] and ["a.b"]"#]
+        );
+    }
+
+    #[test]
+    fn test_entries_keep_typed_values_and_spans() {
+        let document = Document::from_str(
+            "<server port=\"80\">\n  <name>web</name>\n</server>",
+            Format::Xml,
+            ParseOptions::default(),
+        )
+        .unwrap();
+        let entries = document.flatten(FlattenOptions::default());
+        let spans = entries
+            .iter()
+            .map(|entry| (entry.key.as_str(), entry.span))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            spans,
+            [
+                ("server.@port", Some(Span { line: 1, column: 9 })),
+                ("server.name", Some(Span { line: 2, column: 3 })),
+            ]
+        );
+
+        let document = Document::from_str(
+            "[server]\nport = 80\nhosts = [\"a\", \"b\"]\n",
+            Format::Toml,
+            ParseOptions::default(),
+        )
+        .unwrap();
+        let entries = document.flatten(FlattenOptions::default());
+        let spans = entries
+            .iter()
+            .map(|entry| (entry.key.as_str(), entry.span))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            spans,
+            [
+                (
+                    "server.hosts.0",
+                    Some(Span {
+                        line: 3,
+                        column: 10
+                    })
+                ),
+                (
+                    "server.hosts.1",
+                    Some(Span {
+                        line: 3,
+                        column: 15
+                    })
+                ),
+                ("server.port", Some(Span { line: 2, column: 8 })),
+            ]
+        );
+
+        let document = Document::from_str(
+            r#"{ "port": 8080, "debug": false }"#,
+            Format::Json,
+            ParseOptions::default(),
+        )
+        .unwrap();
+        let values = document
+            .flatten(FlattenOptions::default())
+            .into_iter()
+            .map(|entry| entry.value)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            values,
+            [Scalar::Bool(false), Scalar::Number("8080".to_owned())]
+        );
+    }
 }